@@ -0,0 +1,75 @@
+use std::{
+    io,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use macros::retry;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn is_transient(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::ConnectionReset
+}
+
+fn log(err: &io::Error, dur: Duration) {
+    println!("{err}, retrying in {dur:?}");
+}
+
+/// 前两次调用失败，第三次成功
+fn flaky(name: &str) -> io::Result<String> {
+    if CALLS.fetch_add(1, Ordering::SeqCst) % 3 < 2 {
+        return Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+    }
+    Ok(format!("hello {name}"))
+}
+
+#[retry(exponential(min = "10ms", factor = 3, jitter = "equal", max_times = 5), when = is_transient, notify = log)]
+fn fetch(name: &str) -> io::Result<String> {
+    let greeting = flaky(name)?;
+    Ok(greeting)
+}
+
+#[retry(constant(delay = "10ms", max_times = 1))]
+fn always_fail() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+}
+
+/// 函数体移走了按值传入的 name，每次尝试拿到的是它的一份克隆
+#[retry(constant(delay = "10ms", max_times = 3), when = is_transient)]
+async fn shout(mut name: String) -> io::Result<String> {
+    flaky(&name)?;
+    name.push('!');
+    Ok(name)
+}
+
+struct Client {
+    name: String,
+}
+
+impl Client {
+    #[retry(fibonacci(min = "5ms", max = "1s"), when = is_transient)]
+    async fn fetch(&self) -> io::Result<String> {
+        tokio::task::yield_now().await;
+        flaky(&self.name)
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    assert_eq!("hello world", fetch("world")?);
+
+    CALLS.store(0, Ordering::SeqCst);
+    assert!(always_fail().is_err());
+
+    CALLS.store(0, Ordering::SeqCst);
+    let client = Client {
+        name: "tokio".to_string(),
+    };
+    assert_eq!("hello tokio", client.fetch().await?);
+    assert_eq!(3, CALLS.load(Ordering::SeqCst));
+
+    CALLS.store(0, Ordering::SeqCst);
+    assert_eq!("world!", shout("world".to_string()).await?);
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+// #[retry] 属性宏与 derive 的辅助属性 #[retry(...)] 同名，重命名后才能一起导入
+use macros::{retry as retry_fn, RetryClassify};
+use retry::{delay::Fixed, OperationResult};
+use retry_backon::{constant::ConstantBuilder, error::RetryDecision, retry::Retryable};
+
+#[allow(dead_code)]
+#[derive(Debug, RetryClassify)]
+enum ApiError {
+    #[retry(transient)]
+    Timeout,
+    #[retry(after = "retry_after")]
+    RateLimited { retry_after: Duration },
+    #[retry(after = "0")]
+    Unavailable(Option<Duration>),
+    #[retry(after = "hint")]
+    Throttled { hint: RetryHint },
+    #[retry(permanent)]
+    NotFound(String),
+}
+
+/// 建议延迟的字段不必是 Copy
+#[derive(Debug, Clone)]
+struct RetryHint {
+    millis: u64,
+}
+
+impl From<RetryHint> for Option<Duration> {
+    fn from(hint: RetryHint) -> Self {
+        Some(Duration::from_millis(hint.millis))
+    }
+}
+
+/// 缺省分类为 transient，只需标注例外
+#[allow(dead_code)]
+#[derive(Debug, RetryClassify)]
+#[retry(transient)]
+enum IoError {
+    Reset,
+    Refused,
+    #[retry(permanent)]
+    Denied,
+}
+
+/// 标注了 #[retry(transient)] 的变体会被重试
+#[retry_fn(constant(delay = "1ms", max_times = 3), when = IoError::is_retryable)]
+fn connect(errors: &mut Vec<IoError>) -> Result<&'static str, IoError> {
+    errors.pop().map_or(Ok("connected"), Err)
+}
+
+fn decide(err: &ApiError) -> RetryDecision {
+    match err.retry_after() {
+        _ if !err.is_retryable() => RetryDecision::Stop,
+        Some(delay) => RetryDecision::RetryAfter(delay),
+        None => RetryDecision::Retry,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    assert!(ApiError::Timeout.is_retryable());
+    assert!(!ApiError::NotFound("user".to_string()).is_retryable());
+    assert_eq!(None, ApiError::Timeout.retry_after());
+    assert_eq!(
+        Some(Duration::from_secs(3)),
+        ApiError::RateLimited {
+            retry_after: Duration::from_secs(3)
+        }
+        .retry_after()
+    );
+    assert_eq!(None, ApiError::Unavailable(None).retry_after());
+    assert_eq!(
+        Some(Duration::from_millis(20)),
+        ApiError::Throttled {
+            hint: RetryHint { millis: 20 }
+        }
+        .retry_after()
+    );
+    assert!(IoError::Reset.is_retryable());
+    assert!(!IoError::Denied.is_retryable());
+
+    let mut errors = vec![IoError::Refused, IoError::Reset];
+    assert_eq!("connected", connect(&mut errors).unwrap());
+    let mut errors = vec![IoError::Reset, IoError::Denied];
+    assert!(matches!(connect(&mut errors), Err(IoError::Denied)));
+    assert_eq!(1, errors.len());
+
+    let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+
+    // 永久错误不会被重试
+    let mut calls = 0;
+    let result = (|| {
+        calls += 1;
+        async { Err::<(), _>(ApiError::NotFound("user".to_string())) }
+    })
+    .retry(&backoff)
+    .when(ApiError::is_retryable)
+    .await;
+    assert!(matches!(result, Err(ApiError::NotFound(_))));
+    assert_eq!(1, calls);
+
+    // 限流时按错误建议的延迟等待
+    let mut calls = 0;
+    let result = (|| {
+        calls += 1;
+        let first = calls == 1;
+        async move {
+            if first {
+                return Err(ApiError::RateLimited {
+                    retry_after: Duration::from_millis(5),
+                });
+            }
+            Ok(calls)
+        }
+    })
+    .retry(&backoff)
+    .decide(decide)
+    .await;
+    assert_eq!(2, result.unwrap());
+
+    // 与 retry crate 的 OperationResult 一起使用
+    let mut errors = vec![ApiError::Timeout, ApiError::Timeout].into_iter();
+    let result = retry::retry(Fixed::from_millis(1).take(5), || {
+        let result = errors.next().map_or(Ok("done"), Err);
+        OperationResult::classify(result, ApiError::is_retryable)
+    });
+    assert_eq!("done", result.unwrap());
+
+    let mut errors = vec![ApiError::NotFound("user".to_string()), ApiError::Timeout].into_iter();
+    let result = retry::retry(Fixed::from_millis(1).take(5), || {
+        let result = errors.next().map_or(Ok("done"), Err);
+        OperationResult::classify(result, ApiError::is_retryable)
+    });
+    assert!(result.is_err());
+    assert_eq!(1, errors.len());
+}
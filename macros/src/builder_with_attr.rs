@@ -111,7 +111,7 @@ impl BuilderContext {
                 let ty = &f.ty;
                 if !f.optional && f.opts.each.is_some() {
                     let each = Ident::new(f.opts.each.as_deref().unwrap(), name.span());
-                    let (is_vec, ty) = get_vec_inner(&ty);
+                    let (is_vec, ty) = get_vec_inner(ty);
                     if is_vec {
                         return quote! {
                             pub fn #each(mut self, v: impl Into<#ty>) -> Self {
@@ -193,7 +193,7 @@ fn get_type_inner<'a>(ty: &'a Type, name: &str) -> (bool, &'a Type) {
             }
         }
     }
-    return (false, ty);
+    (false, ty)
 }
 
 #[derive(Debug, Default, FromField)]
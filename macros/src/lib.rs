@@ -1,8 +1,13 @@
 mod builder;
 mod builder_with_attr;
+// 与 retry-backon 配置文件共用同一份时长格式
+#[path = "../../retry-backon/src/duration.rs"]
+mod duration;
+mod retry;
+mod retry_classify;
 
 use proc_macro::TokenStream;
-use syn::{parse_macro_input, DeriveInput};
+use syn::{parse_macro_input, DeriveInput, ItemFn};
 #[proc_macro]
 pub fn query(input: TokenStream) -> TokenStream {
     println!("{:#?}", input);
@@ -24,3 +29,22 @@ pub fn derive_builder_with_attr(input: TokenStream) -> TokenStream {
         .render()
         .into()
 }
+#[proc_macro_attribute]
+pub fn retry(args: TokenStream, input: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(args as retry::RetryArgs);
+    let func = parse_macro_input!(input as ItemFn);
+    match retry::RetryContext::new(args, func) {
+        Ok(ctx) => ctx.render().into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+// 变体上的 #[retry(...)] 是 derive 的辅助属性，与 #[retry] 属性宏同时导入时会报 E0659 歧义，
+// 这时重命名属性宏再导入：use macros::retry as retry_fn; 然后用 #[retry_fn(...)] 修饰函数
+#[proc_macro_derive(RetryClassify, attributes(retry))]
+pub fn derive_retry_classify(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match retry_classify::ClassifyContext::new(&input) {
+        Ok(ctx) => ctx.render().into(),
+        Err(e) => e.write_errors().into(),
+    }
+}
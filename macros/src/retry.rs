@@ -0,0 +1,413 @@
+use proc_macro2::{Ident, TokenStream};
+use quote::{quote, quote_spanned};
+use syn::{
+    parenthesized,
+    parse::{Parse, ParseStream},
+    punctuated::Punctuated,
+    spanned::Spanned,
+    Error, FnArg, ItemFn, Lit, Pat, PatIdent, Path, ReturnType, Signature, Token, Type,
+};
+
+use crate::duration::parse_duration;
+
+/// 退避策略的参数取值类型
+#[derive(Clone, Copy)]
+enum Value {
+    Duration,
+    Times,
+    Factor,
+    Jitter,
+}
+
+/// 每种退避策略支持的参数：(参数名, builder 方法, 取值类型)
+type Settings = &'static [(&'static str, &'static str, Value)];
+
+const CONSTANT: Settings = &[
+    ("delay", "with_delay", Value::Duration),
+    ("max_times", "with_max_times", Value::Times),
+    ("total_delay", "with_total_delay", Value::Duration),
+];
+const EXPONENTIAL: Settings = &[
+    ("min", "with_min_delay", Value::Duration),
+    ("max", "with_max_delay", Value::Duration),
+    ("factor", "with_factor", Value::Factor),
+    ("jitter", "with_jitter_mode", Value::Jitter),
+    ("max_times", "with_max_times", Value::Times),
+    ("total_delay", "with_total_delay", Value::Duration),
+];
+const FIBONACCI: Settings = &[
+    ("min", "with_min_delay", Value::Duration),
+    ("max", "with_max_delay", Value::Duration),
+    ("max_times", "with_max_times", Value::Times),
+    ("total_delay", "with_total_delay", Value::Duration),
+];
+const LINEAR: Settings = &[
+    ("step", "with_step", Value::Duration),
+    ("min", "with_min_delay", Value::Duration),
+    ("max", "with_max_delay", Value::Duration),
+    ("max_times", "with_max_times", Value::Times),
+    ("total_delay", "with_total_delay", Value::Duration),
+];
+const DECORRELATED_JITTER: Settings = &[
+    ("base", "with_base_delay", Value::Duration),
+    ("max", "with_max_delay", Value::Duration),
+    ("max_times", "with_max_times", Value::Times),
+    ("total_delay", "with_total_delay", Value::Duration),
+];
+
+/// 退避策略名 -> (builder 类型, 支持的参数)
+fn backoff_kind(name: &str) -> Option<(TokenStream, Settings)> {
+    let kind = match name {
+        "constant" => (quote!(::retry_backon::constant::ConstantBuilder), CONSTANT),
+        "exponential" => (
+            quote!(::retry_backon::exponential::ExponentialBuilder),
+            EXPONENTIAL,
+        ),
+        "fibonacci" => (
+            quote!(::retry_backon::fibonacci::FibonacciBuilder),
+            FIBONACCI,
+        ),
+        "linear" => (quote!(::retry_backon::linear::LinearBuilder), LINEAR),
+        "decorrelated_jitter" => (
+            quote!(::retry_backon::decorrelated_jitter::DecorrelatedJitterBuilder),
+            DECORRELATED_JITTER,
+        ),
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// 形如 `min = "100ms"` 的一个参数
+struct Setting {
+    name: Ident,
+    value: Lit,
+}
+
+impl Parse for Setting {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = input.parse()?;
+        Ok(Self { name, value })
+    }
+}
+
+/// `#[retry(...)]` 中的一项
+enum Arg {
+    /// exponential(min = "100ms", max_times = 5)
+    Backoff(Ident, Punctuated<Setting, Token![,]>),
+    /// when = path::to::is_transient
+    When(Path),
+    /// notify = path::to::log
+    Notify(Path),
+}
+
+impl Parse for Arg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: Ident = input.parse()?;
+        if input.peek(syn::token::Paren) {
+            let content;
+            parenthesized!(content in input);
+            return Ok(Arg::Backoff(
+                name,
+                content.parse_terminated(Setting::parse)?,
+            ));
+        }
+        if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            return match name.to_string().as_str() {
+                "when" => Ok(Arg::When(input.parse()?)),
+                "notify" => Ok(Arg::Notify(input.parse()?)),
+                _ => Err(Error::new(
+                    name.span(),
+                    "unknown argument, expected `when` or `notify`",
+                )),
+            };
+        }
+        // 不带参数的退避策略，比如 #[retry(fibonacci)]
+        Ok(Arg::Backoff(name, Punctuated::new()))
+    }
+}
+
+/// 解析后的 `#[retry(...)]` 参数
+#[derive(Default)]
+pub struct RetryArgs {
+    backoff: Option<TokenStream>,
+    when: Option<Path>,
+    notify: Option<Path>,
+}
+
+impl Parse for RetryArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = RetryArgs::default();
+        for arg in Punctuated::<Arg, Token![,]>::parse_terminated(input)? {
+            match arg {
+                Arg::Backoff(name, settings) => {
+                    if args.backoff.is_some() {
+                        return Err(Error::new(name.span(), "only one backoff can be given"));
+                    }
+                    args.backoff = Some(render_backoff(&name, settings)?);
+                }
+                Arg::When(path) => set_once(&mut args.when, path, "when")?,
+                Arg::Notify(path) => set_once(&mut args.notify, path, "notify")?,
+            }
+        }
+        Ok(args)
+    }
+}
+
+fn set_once(slot: &mut Option<Path>, path: Path, name: &str) -> syn::Result<()> {
+    if slot.is_some() {
+        return Err(Error::new(path.span(), format!("`{name}` is given twice")));
+    }
+    *slot = Some(path);
+    Ok(())
+}
+
+/// 把 exponential(min = "100ms") 转换成 ExponentialBuilder::default().with_min_delay(...)
+fn render_backoff(
+    name: &Ident,
+    settings: Punctuated<Setting, Token![,]>,
+) -> syn::Result<TokenStream> {
+    let (builder, known) = backoff_kind(&name.to_string()).ok_or_else(|| {
+        Error::new(
+            name.span(),
+            "unknown backoff, expected one of `constant`, `exponential`, `fibonacci`, `linear` or `decorrelated_jitter`",
+        )
+    })?;
+    let mut calls = Vec::new();
+    for Setting { name: key, value } in settings {
+        let (_, method, kind) = known.iter().find(|(k, ..)| key == k).ok_or_else(|| {
+            let expected: Vec<_> = known.iter().map(|(k, ..)| format!("`{k}`")).collect();
+            Error::new(
+                key.span(),
+                format!(
+                    "unknown setting of `{name}`, expected one of {}",
+                    expected.join(", ")
+                ),
+            )
+        })?;
+        let method = Ident::new(method, key.span());
+        calls.push(match (kind, &value) {
+            (Value::Duration, Lit::Str(s)) => {
+                let d = parse_duration(&s.value()).ok_or_else(|| {
+                    Error::new(
+                        s.span(),
+                        "invalid duration, expected a number followed by ms, s, m or h",
+                    )
+                })?;
+                let (secs, nanos) = (d.as_secs(), d.subsec_nanos());
+                quote!(.#method(::std::time::Duration::new(#secs, #nanos)))
+            }
+            (Value::Times, Lit::Int(n)) => {
+                n.base10_parse::<usize>()?;
+                quote!(.#method(#n))
+            }
+            (Value::Factor, Lit::Float(_) | Lit::Int(_)) => {
+                let f = parse_factor(&value)?;
+                quote!(.#method(#f))
+            }
+            // jitter = true 与 jitter = "additive" 相同
+            (Value::Jitter, Lit::Bool(b)) => {
+                let mode = if b.value { "Additive" } else { "None" };
+                let mode = Ident::new(mode, b.span());
+                quote!(.#method(::retry_backon::exponential::Jitter::#mode))
+            }
+            (Value::Jitter, Lit::Str(s)) => {
+                let mode = match s.value().as_str() {
+                    "none" => "None",
+                    "full" => "Full",
+                    "equal" => "Equal",
+                    "additive" => "Additive",
+                    _ => {
+                        return Err(Error::new(
+                            s.span(),
+                            "unknown jitter, expected one of \"none\", \"full\", \"equal\" or \"additive\"",
+                        ))
+                    }
+                };
+                let mode = Ident::new(mode, s.span());
+                quote!(.#method(::retry_backon::exponential::Jitter::#mode))
+            }
+            (kind, _) => {
+                let expected = match kind {
+                    Value::Duration => "a duration string like \"100ms\"",
+                    Value::Times => "an integer",
+                    Value::Factor => "a number",
+                    Value::Jitter => "`true`, `false` or a jitter mode like \"full\"",
+                };
+                return Err(Error::new(value.span(), format!("expected {expected}")));
+            }
+        });
+    }
+    Ok(quote!(#builder::default() #(#calls)*))
+}
+
+/// 增长系数必须大于 1，否则延迟不会增长
+fn parse_factor(value: &Lit) -> syn::Result<f32> {
+    let f: f32 = match value {
+        Lit::Float(f) => f.base10_parse()?,
+        Lit::Int(n) => n.base10_parse()?,
+        _ => unreachable!("matched by render_backoff"),
+    };
+    if f <= 1.0 || !f.is_finite() {
+        return Err(Error::new(value.span(), "factor must be greater than 1"));
+    }
+    Ok(f)
+}
+
+/// 被 `#[retry]` 修饰的函数
+pub struct RetryContext {
+    args: RetryArgs,
+    func: ItemFn,
+}
+
+impl RetryContext {
+    pub fn new(args: RetryArgs, func: ItemFn) -> syn::Result<Self> {
+        let sig = &func.sig;
+        if let Some(constness) = &sig.constness {
+            return Err(Error::new(
+                constness.span(),
+                "#[retry] can't be used on a const fn",
+            ));
+        }
+        if let Some(variadic) = &sig.variadic {
+            return Err(Error::new(
+                variadic.span(),
+                "#[retry] can't be used on a variadic fn",
+            ));
+        }
+        for arg in &sig.inputs {
+            let by_value_self = match arg {
+                FnArg::Receiver(r) => r.reference.is_none(),
+                FnArg::Typed(t) => is_self(&t.pat) && !matches!(*t.ty, Type::Reference(_)),
+            };
+            if by_value_self {
+                return Err(Error::new(
+                    arg.span(),
+                    "#[retry] can't take `self` by value, the body runs again for each attempt, take `&self` instead",
+                ));
+            }
+        }
+        match &sig.output {
+            ReturnType::Type(_, ty) if is_result(ty) => {}
+            ReturnType::Type(_, ty) => {
+                return Err(Error::new(
+                    ty.span(),
+                    "#[retry] needs a function returning a `Result`",
+                ))
+            }
+            ReturnType::Default => {
+                return Err(Error::new(
+                    sig.fn_token.span(),
+                    "#[retry] needs a function returning a `Result`",
+                ))
+            }
+        }
+        Ok(Self { args, func })
+    }
+
+    pub fn render(&self) -> TokenStream {
+        let ItemFn {
+            attrs,
+            vis,
+            sig,
+            block,
+        } = &self.func;
+        let ReturnType::Type(_, output) = &sig.output else {
+            unreachable!("checked by RetryContext::new")
+        };
+        // 函数体每次尝试都会重新执行，按值传入的参数可能被它移走，所以每次尝试克隆一份
+        let mut sig = sig.clone();
+        let clones = clone_args(&mut sig);
+        let backoff =
+            self.args.backoff.clone().unwrap_or_else(|| {
+                quote!(::retry_backon::exponential::ExponentialBuilder::default())
+            });
+        // 函数名同时作为 tracing / metrics 中的 operation
+        let name = sig.ident.to_string();
+        let when = self.args.when.as_ref().map(|p| quote!(.when(#p)));
+        let notify = self.args.notify.as_ref().map(|p| quote!(.notify(#p)));
+
+        // 每次尝试都重新执行原函数体，返回类型标注在闭包和 async 块上，这样 `?` 才能推断出错误类型
+        let body = if sig.asyncness.is_some() {
+            quote! {
+                ::retry_backon::retry::Retryable::retry(
+                    || {
+                        #(#clones)*
+                        async move {
+                            let output: #output = async #block.await;
+                            output
+                        }
+                    },
+                    &#backoff,
+                )
+                .with_name(#name)
+                #when
+                #notify
+                .await
+            }
+        } else {
+            quote! {
+                ::retry_backon::blocking_retry::BlockingRetryable::retry(
+                    || -> #output {
+                        #(#clones)*
+                        #block
+                    },
+                    &#backoff,
+                )
+                .with_name(#name)
+                #when
+                #notify
+                .call()
+            }
+        };
+        quote! {
+            #(#attrs)*
+            #vis #sig {
+                #body
+            }
+        }
+    }
+}
+
+/// 为每个按值传入的参数生成 `let x = Clone::clone(&x);`，引用不用克隆。
+/// 克隆的是外层的参数，所以去掉它在签名中的 `mut`，免得 unused_mut 警告
+fn clone_args(sig: &mut Signature) -> Vec<TokenStream> {
+    let mut clones = Vec::new();
+    for arg in &mut sig.inputs {
+        let FnArg::Typed(arg) = arg else { continue };
+        if matches!(*arg.ty, Type::Reference(_)) {
+            continue;
+        }
+        if let Pat::Ident(PatIdent {
+            by_ref: None,
+            mutability,
+            ident,
+            subpat: None,
+            ..
+        }) = &mut *arg.pat
+        {
+            // 类型没有实现 Clone 时，错误指向参数的类型
+            let mutability = mutability.take();
+            clones.push(quote_spanned! {arg.ty.span()=>
+                let #mutability #ident = ::std::clone::Clone::clone(&#ident);
+            });
+        }
+    }
+    clones
+}
+
+fn is_self(pat: &Pat) -> bool {
+    matches!(pat, Pat::Ident(p) if p.ident == "self")
+}
+
+// 返回类型的最后一段是否为 Result，比如 Result<T, E>、io::Result<T>、anyhow::Result<T>
+fn is_result(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p.path.segments.last().is_some_and(|s| s.ident == "Result"),
+        Type::Paren(p) => is_result(&p.elem),
+        Type::Group(g) => is_result(&g.elem),
+        _ => false,
+    }
+}
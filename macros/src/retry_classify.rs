@@ -0,0 +1,188 @@
+use darling::{
+    ast::{Data, Fields},
+    util::{Flag, SpannedValue},
+    Error, FromDeriveInput, FromField, FromVariant,
+};
+use proc_macro2::{Ident, TokenStream};
+use quote::quote;
+use syn::{DeriveInput, Generics, Index, Member};
+
+/// enum 上的 #[retry(...)]，给出未标注变体的缺省分类
+#[derive(Debug, FromDeriveInput)]
+#[darling(attributes(retry), supports(enum_any))]
+struct EnumOpts {
+    ident: Ident,
+    generics: Generics,
+    data: Data<VariantOpts, ()>,
+    transient: Flag,
+    permanent: Flag,
+}
+
+/// 变体上的 #[retry(transient)]、#[retry(permanent)] 或 #[retry(after = "field")]
+#[derive(Debug, FromVariant)]
+#[darling(attributes(retry))]
+struct VariantOpts {
+    ident: Ident,
+    fields: Fields<FieldOpts>,
+    transient: Flag,
+    permanent: Flag,
+    after: Option<SpannedValue<String>>,
+}
+
+#[derive(Debug, FromField)]
+struct FieldOpts {
+    ident: Option<Ident>,
+}
+
+/// 一个变体的分类结果
+struct Variant {
+    ident: Ident,
+    retryable: bool,
+    // 建议延迟所在的字段
+    after: Option<Member>,
+}
+
+pub struct ClassifyContext {
+    name: Ident,
+    generics: Generics,
+    variants: Vec<Variant>,
+}
+
+impl ClassifyContext {
+    pub fn new(input: &DeriveInput) -> darling::Result<Self> {
+        let opts = EnumOpts::from_derive_input(input)?;
+        let (transient, permanent) = (opts.transient.is_present(), opts.permanent.is_present());
+        if transient && permanent {
+            return Err(
+                Error::custom("an enum can't default to both `transient` and `permanent`")
+                    .with_span(&opts.ident),
+            );
+        }
+        let default = match (transient, permanent) {
+            (true, _) => Some(true),
+            (_, true) => Some(false),
+            _ => None,
+        };
+
+        let variants = opts
+            .data
+            .take_enum()
+            .expect("supports(enum_any) only accepts enums");
+        // 空 enum 没有值可以分类，生成的 match 也无法编译
+        if variants.is_empty() {
+            return Err(
+                Error::custom("RetryClassify needs at least one variant").with_span(&opts.ident)
+            );
+        }
+
+        let mut errors = Error::accumulator();
+        let variants = variants
+            .into_iter()
+            .filter_map(|v| errors.handle(Variant::new(v, default)))
+            .collect();
+        errors.finish()?;
+
+        Ok(Self {
+            name: opts.ident,
+            generics: opts.generics,
+            variants,
+        })
+    }
+
+    pub fn render(&self) -> TokenStream {
+        let name = &self.name;
+        let (impl_generics, ty_generics, where_clause) = self.generics.split_for_impl();
+
+        let retryable = self.variants.iter().map(|v| {
+            let ident = &v.ident;
+            let retryable = v.retryable;
+            quote! { Self::#ident { .. } => #retryable }
+        });
+        // 字段只需实现 Clone 和 Into<Option<Duration>>，不要求 Copy
+        let after = self.variants.iter().filter_map(|v| {
+            let ident = &v.ident;
+            let field = v.after.as_ref()?;
+            Some(quote! {
+                Self::#ident { #field: after, .. } => {
+                    ::std::convert::Into::<::std::option::Option<::std::time::Duration>>::into(
+                        ::std::clone::Clone::clone(after),
+                    )
+                }
+            })
+        });
+
+        quote! {
+            impl #impl_generics #name #ty_generics #where_clause {
+                /// 是否值得重试，可以直接传给 `Retry::when`
+                pub fn is_retryable(&self) -> bool {
+                    match self {
+                        #(#retryable,)*
+                    }
+                }
+
+                /// 错误建议的重试延迟，来自 #[retry(after = "field")] 标注的字段
+                pub fn retry_after(&self) -> ::std::option::Option<::std::time::Duration> {
+                    #[allow(unreachable_patterns)]
+                    match self {
+                        #(#after)*
+                        _ => ::std::option::Option::None,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Variant {
+    fn new(opts: VariantOpts, default: Option<bool>) -> darling::Result<Self> {
+        let ident = opts.ident;
+        let given = [
+            opts.transient.is_present(),
+            opts.permanent.is_present(),
+            opts.after.is_some(),
+        ];
+        let retryable = match given {
+            [false, false, false] => default.ok_or_else(|| {
+                Error::custom(
+                    "unclassified variant, add #[retry(transient)], #[retry(permanent)] or #[retry(after = \"field\")]",
+                )
+                .with_span(&ident)
+            })?,
+            [true, false, false] | [false, false, true] => true,
+            [false, true, false] => false,
+            _ => {
+                return Err(Error::custom(
+                    "`transient`, `permanent` and `after` can't be combined",
+                )
+                .with_span(&ident))
+            }
+        };
+
+        // after = "field" 指向具名字段，after = "0" 指向元组变体的字段
+        let after = match &opts.after {
+            None => None,
+            Some(field) => {
+                let found = opts
+                    .fields
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, f)| match &f.ident {
+                        Some(name) if name == field.as_str() => Some(Member::Named(name.clone())),
+                        None if i.to_string() == **field => Some(Member::Unnamed(Index::from(i))),
+                        _ => None,
+                    });
+                let member = found.ok_or_else(|| {
+                    Error::custom(format!("variant `{ident}` has no field `{}`", **field))
+                        .with_span(field)
+                })?;
+                Some(member)
+            }
+        };
+
+        Ok(Self {
+            ident,
+            retryable,
+            after,
+        })
+    }
+}
@@ -0,0 +1,17 @@
+use macros::RetryClassify;
+
+#[derive(RetryClassify)]
+enum ApiError {
+    #[retry(transient, permanent)]
+    Timeout,
+    #[retry(permanent, after = "0")]
+    RateLimited(std::time::Duration),
+}
+
+#[derive(RetryClassify)]
+#[retry(transient, permanent)]
+enum IoError {
+    Reset,
+}
+
+fn main() {}
@@ -0,0 +1,6 @@
+use macros::RetryClassify;
+
+#[derive(RetryClassify)]
+enum Never {}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use macros::RetryClassify;
+
+#[derive(RetryClassify)]
+enum ApiError {
+    #[retry(transient)]
+    Timeout,
+    NotFound,
+}
+
+fn main() {}
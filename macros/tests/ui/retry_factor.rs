@@ -0,0 +1,13 @@
+use macros::retry;
+
+#[retry(exponential(factor = 1))]
+fn fetch() -> std::io::Result<()> {
+    Ok(())
+}
+
+#[retry(exponential(factor = 0.5))]
+fn fetch_again() -> std::io::Result<()> {
+    Ok(())
+}
+
+fn main() {}
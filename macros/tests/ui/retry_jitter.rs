@@ -0,0 +1,13 @@
+use macros::retry;
+
+#[retry(exponential(jitter = "random"))]
+fn fetch() -> std::io::Result<()> {
+    Ok(())
+}
+
+#[retry(exponential(jitter = 1))]
+fn fetch_again() -> std::io::Result<()> {
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use macros::retry;
+
+struct Connection;
+
+impl Connection {
+    fn close(self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// the body runs again for each attempt, so by-value arguments are cloned
+#[retry(constant(delay = "10ms"))]
+fn close(conn: Connection) -> std::io::Result<()> {
+    conn.close()
+}
+
+fn main() {}
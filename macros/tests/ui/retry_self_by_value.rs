@@ -0,0 +1,12 @@
+use macros::retry;
+
+struct Connection;
+
+impl Connection {
+    #[retry(constant(delay = "10ms"))]
+    fn close(self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn main() {}
@@ -1,9 +1,204 @@
-use std::{fmt::Debug, time::Duration};
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+use web_time::{Instant, SystemTime};
+
+use crate::sleep::system_time;
 
 pub trait BackoffBuilder: Clone + Debug + Send + Sync + Unpin {
     type Backoff: Backoff;
     fn build(&self) -> Self::Backoff;
 }
 
-pub trait Backoff: Iterator<Item = Duration> + Send + Sync + Unpin {}
-impl<T> Backoff for T where T: Iterator<Item = Duration> + Send + Sync + Unpin {}
+/// Backoff is an iterator of delays, with hooks used by the retries for their clock,
+/// the delays asked by errors and why it ended.
+/// A custom iterator only needs an empty `impl Backoff`.
+pub trait Backoff: Iterator<Item = Duration> + Send + Sync + Unpin {
+    /// Like `next`, with the deadline checked against `now`, the time of the retry's clock.
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let _ = now;
+        self.next()
+    }
+    /// Take a step of the backoff but wait `delay` instead, e.g. the delay asked by
+    /// a rate-limited API. The delay is still bounded by the limits of the builder.
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        self.next_at(now).map(|_| delay)
+    }
+    /// Whether the backoff ended because the next delay would end after its deadline.
+    fn deadline_reached(&self) -> bool {
+        false
+    }
+    /// Called once the operation succeeded, e.g. to forget a saved state.
+    fn succeeded(&mut self) {}
+}
+
+impl<B: Backoff + ?Sized> Backoff for Box<B> {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        (**self).next_at(now)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        (**self).next_after(now, delay)
+    }
+    fn deadline_reached(&self) -> bool {
+        (**self).deadline_reached()
+    }
+    fn succeeded(&mut self) {
+        (**self).succeeded()
+    }
+}
+
+/// ResumableBuilder is a builder whose backoffs can be saved and resumed,
+/// e.g. to continue the schedule after a restart.
+pub trait ResumableBuilder: BackoffBuilder {
+    /// Build a backoff which continues the schedule saved in `state`.
+    fn resume(&self, state: &BackoffState) -> Self::Backoff;
+    /// Save the progress of `backoff`, `last_failure` is left for the caller to set.
+    fn state(backoff: &Self::Backoff) -> BackoffState;
+}
+
+/// BackoffState is the progress of a backoff, it can be saved
+/// and given back to the builder to resume the schedule, e.g. after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackoffState {
+    /// Number of delays already taken.
+    pub attempts: usize,
+    /// The last delay before jitter.
+    pub current_delay: Option<Duration>,
+    /// Sum of the delays taken, counted against the total delay of the builder.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub slept: Duration,
+    /// When the last delay was taken.
+    pub last_failure: Option<SystemTime>,
+}
+
+impl BackoffState {
+    /// Record a failure at `now`, the time of the retry's clock,
+    /// so a virtual clock saves virtual timestamps too.
+    pub fn failed_at(&mut self, now: Instant) {
+        self.last_failure = Some(system_time(now));
+    }
+    /// The part of the last delay not slept yet.
+    pub fn remaining_delay(&self) -> Duration {
+        match (self.current_delay, self.last_failure) {
+            (Some(delay), Some(last)) => {
+                delay.saturating_sub(last.elapsed().unwrap_or(Duration::ZERO))
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// DynBackoffBuilder erases the type of a builder, so the backoff kind
+/// can be picked at runtime, e.g. from a config file.
+#[derive(Debug, Clone)]
+pub struct DynBackoffBuilder(Arc<dyn ErasedBuilder>);
+
+impl DynBackoffBuilder {
+    pub fn new<B>(builder: B) -> Self
+    where
+        B: BackoffBuilder + 'static,
+        B::Backoff: 'static,
+    {
+        Self(Arc::new(builder))
+    }
+}
+
+impl BackoffBuilder for DynBackoffBuilder {
+    type Backoff = Box<dyn Backoff>;
+    fn build(&self) -> Self::Backoff {
+        self.0.build_boxed()
+    }
+}
+
+trait ErasedBuilder: Debug + Send + Sync {
+    fn build_boxed(&self) -> Box<dyn Backoff>;
+}
+
+impl<B> ErasedBuilder for B
+where
+    B: BackoffBuilder,
+    B::Backoff: 'static,
+{
+    fn build_boxed(&self) -> Box<dyn Backoff> {
+        Box::new(self.build())
+    }
+}
+
+/// DelayLimit bounds the delays of a backoff by their sum and by a deadline.
+/// The delay crossing a limit is clipped to fit, then the backoff ends.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DelayLimit {
+    pub(crate) total_delay: Option<Duration>,
+    pub(crate) deadline: Option<Instant>,
+
+    slept: Duration,
+    exhausted: bool,
+    deadline_reached: bool,
+}
+
+impl DelayLimit {
+    pub(crate) fn clip(&mut self, now: Instant, delay: Option<Duration>) -> Option<Duration> {
+        if self.exhausted {
+            return None;
+        }
+        let mut delay = delay?;
+        if let Some(total_delay) = self.total_delay {
+            let remaining = total_delay.saturating_sub(self.slept);
+            if delay >= remaining {
+                delay = remaining;
+                self.exhausted = true;
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(now);
+            if delay >= remaining {
+                delay = remaining;
+                self.exhausted = true;
+                self.deadline_reached = true;
+            }
+        }
+        if self.exhausted && delay.is_zero() {
+            return None;
+        }
+        self.slept = self.slept.saturating_add(delay);
+        Some(delay)
+    }
+    pub(crate) fn deadline_reached(&self) -> bool {
+        self.deadline_reached
+    }
+    pub(crate) fn slept(&self) -> Duration {
+        self.slept
+    }
+    /// Continue from the delays already taken, so the total delay is not spent twice.
+    pub(crate) fn resume(&mut self, slept: Duration) {
+        self.slept = slept;
+        if self
+            .total_delay
+            .is_some_and(|total_delay| slept >= total_delay)
+        {
+            self.exhausted = true;
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::{constant::ConstantBuilder, fibonacci::FibonacciBuilder};
+
+    use super::*;
+    #[test]
+    fn test_dyn_backoff_builder() {
+        let builders = [
+            DynBackoffBuilder::new(ConstantBuilder::default()),
+            DynBackoffBuilder::new(FibonacciBuilder::default().with_max_times(4)),
+        ];
+        let delays: Vec<Vec<_>> = builders.iter().map(|b| b.build().collect()).collect();
+        assert_eq!(vec![Duration::from_secs(1); 3], delays[0]);
+        assert_eq!(
+            vec![1, 1, 2, 3]
+                .into_iter()
+                .map(Duration::from_secs)
+                .collect::<Vec<_>>(),
+            delays[1]
+        );
+    }
+}
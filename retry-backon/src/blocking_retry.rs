@@ -1,6 +1,12 @@
-use std::{thread, time::Duration};
+use std::time::Duration;
 
-use crate::backoff::{Backoff, BackoffBuilder};
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    cancel::CancellationToken,
+    error::{Cancelled, IntoCancelledError, Report, RetryDecision, RetryError, StopReason},
+    fallback::{BlockingFallback, FallbackValue},
+    sleep::{BlockingSleeper, ThreadSleeper},
+};
 
 pub trait BlockingRetryable<B: BackoffBuilder, T, E, F: FnMut() -> Result<T, E>> {
     fn retry(self, builder: &B) -> BlockingRetry<B::Backoff, T, E, F>;
@@ -16,11 +22,21 @@ where
     }
 }
 
-pub struct BlockingRetry<B: Backoff, T, E, F: FnMut() -> Result<T, E>> {
+pub struct BlockingRetry<
+    B: Backoff,
+    T,
+    E,
+    F: FnMut() -> Result<T, E>,
+    SF: BlockingSleeper = ThreadSleeper,
+> {
     backoff: B,
     retryable: fn(&E) -> bool,
+    decide: fn(&E) -> RetryDecision,
     notify: fn(&E, Duration),
+    cancellation: Option<(CancellationToken, IntoCancelledError<E>)>,
+    report: Report<E>,
     f: F,
+    sleeper: SF,
 }
 
 impl<B, T, E, F> BlockingRetry<B, T, E, F>
@@ -32,8 +48,33 @@ where
         BlockingRetry {
             backoff,
             retryable: |_: &E| true,
+            decide: |_: &E| RetryDecision::Retry,
             notify: |_: &E, _: Duration| {},
+            cancellation: None,
+            report: Report::new(),
             f,
+            sleeper: ThreadSleeper,
+        }
+    }
+}
+
+impl<B, T, E, F, SF> BlockingRetry<B, T, E, F, SF>
+where
+    B: Backoff,
+    F: FnMut() -> Result<T, E>,
+    SF: BlockingSleeper,
+{
+    /// Replace the sleeper used to wait between attempts, e.g. a `TestClock`.
+    pub fn sleep<SN: BlockingSleeper>(self, sleeper: SN) -> BlockingRetry<B, T, E, F, SN> {
+        BlockingRetry {
+            backoff: self.backoff,
+            retryable: self.retryable,
+            decide: self.decide,
+            notify: self.notify,
+            cancellation: self.cancellation,
+            report: self.report,
+            f: self.f,
+            sleeper,
         }
     }
 
@@ -42,28 +83,119 @@ where
         self
     }
 
+    /// Decide how to retry an error accepted by `when`,
+    /// e.g. to wait for the delay asked by a rate-limited API.
+    pub fn decide(mut self, decide: fn(&E) -> RetryDecision) -> Self {
+        self.decide = decide;
+        self
+    }
+
     pub fn notify(mut self, notify: fn(&E, Duration)) -> Self {
         self.notify = notify;
         self
     }
 
-    pub fn call(mut self) -> Result<T, E> {
+    /// Stop retrying once `token` is cancelled, a `Cancelled` converted into `E`
+    /// is returned if the retry was sleeping or not started yet.
+    /// An attempt in flight can't be aborted, its result is returned as is.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self
+    where
+        E: From<Cancelled>,
+    {
+        self.cancellation = Some((token, E::from));
+        self
+    }
+
+    /// Name the operation in the spans and metrics of the `tracing` and `metrics` features.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.report.instrument.name = name;
+        self
+    }
+
+    pub fn call(self) -> Result<T, E> {
+        self.run().map_err(RetryError::into_inner)
+    }
+
+    /// Like `call`, but returns `RetryError` on failure, which keeps every error met,
+    /// the number of attempts, the elapsed time and why the retry gave up.
+    pub fn call_with_report(mut self) -> Result<T, RetryError<E>> {
+        self.report.collect = true;
+        self.run()
+    }
+
+    /// Call `fallback` with the final `RetryError` once the retry gives up,
+    /// its result is returned instead, e.g. to serve a stale cache entry.
+    pub fn fallback<FB: BlockingFallback<T, E>>(
+        self,
+        fallback: FB,
+    ) -> BlockingFallbackRetry<B, T, E, F, FB, SF> {
+        BlockingFallbackRetry {
+            inner: self,
+            fallback,
+        }
+    }
+
+    /// Return `Ok(value)` once the retry gives up.
+    pub fn fallback_value(
+        self,
+        value: T,
+    ) -> BlockingFallbackRetry<B, T, E, F, FallbackValue<T>, SF> {
+        self.fallback(FallbackValue(value))
+    }
+
+    fn run(mut self) -> Result<T, RetryError<E>> {
         loop {
-            let result = (self.f)();
+            if let Some((token, into_err)) = &self.cancellation {
+                if token.is_cancelled() {
+                    return Err(self.report.give_up(
+                        into_err(Cancelled),
+                        StopReason::Cancelled,
+                        self.sleeper.now(),
+                    ));
+                }
+            }
+            self.report.start(self.sleeper.now());
+            self.report.start_attempt();
+            let result = self.report.instrument.in_span(|| (self.f)());
 
             match result {
-                Ok(v) => return Ok(v),
+                Ok(v) => {
+                    self.backoff.succeeded();
+                    self.report.succeed();
+                    return Ok(v);
+                }
                 Err(err) => {
                     if !(self.retryable)(&err) {
-                        return Err(err);
+                        return Err(self.report.give_up(
+                            err,
+                            StopReason::NotRetryable,
+                            self.sleeper.now(),
+                        ));
                     }
-
-                    match self.backoff.next() {
-                        None => return Err(err),
-                        Some(dur) => {
-                            (self.notify)(&err, dur);
-                            thread::sleep(dur);
+                    let cancelled = self
+                        .cancellation
+                        .as_ref()
+                        .is_some_and(|(token, _)| token.is_cancelled());
+                    let dur = self.report.next_delay(
+                        err,
+                        &mut self.backoff,
+                        self.decide,
+                        self.notify,
+                        cancelled,
+                        self.sleeper.now(),
+                    )?;
+                    match &self.cancellation {
+                        Some((token, into_err)) => {
+                            if self.sleeper.sleep_cancellable(dur, token) {
+                                let err = into_err(Cancelled);
+                                return Err(self.report.give_up(
+                                    err,
+                                    StopReason::Cancelled,
+                                    self.sleeper.now(),
+                                ));
+                            }
                         }
+                        None => self.sleeper.sleep(dur),
                     }
                 }
             }
@@ -71,11 +203,39 @@ where
     }
 }
 
+/// BlockingFallbackRetry is a `BlockingRetry` calling a fallback when it gives up,
+/// created by `BlockingRetry::fallback` and `BlockingRetry::fallback_value`.
+pub struct BlockingFallbackRetry<B, T, E, F, FB, SF = ThreadSleeper>
+where
+    B: Backoff,
+    F: FnMut() -> Result<T, E>,
+    FB: BlockingFallback<T, E>,
+    SF: BlockingSleeper,
+{
+    inner: BlockingRetry<B, T, E, F, SF>,
+    fallback: FB,
+}
+
+impl<B, T, E, F, FB, SF> BlockingFallbackRetry<B, T, E, F, FB, SF>
+where
+    B: Backoff,
+    F: FnMut() -> Result<T, E>,
+    FB: BlockingFallback<T, E>,
+    SF: BlockingSleeper,
+{
+    pub fn call(self) -> Result<T, E> {
+        match self.inner.run() {
+            Ok(v) => Ok(v),
+            Err(err) => self.fallback.call(err),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::sync::Mutex;
+    use std::{sync::Mutex, thread};
 
-    use crate::exponential::ExponentialBuilder;
+    use crate::{exponential::ExponentialBuilder, sleep::TestClock};
 
     use super::*;
     use anyhow::Result;
@@ -131,4 +291,129 @@ mod tests {
 
         Ok(())
     }
+    #[test]
+    fn test_retry_with_cancellation() -> Result<()> {
+        let token = CancellationToken::new();
+        let cloned = token.clone();
+        let handle = thread::spawn(move || {
+            always_error
+                .retry(&ExponentialBuilder::default().with_min_delay(Duration::from_secs(60)))
+                .with_cancellation(cloned)
+                .call()
+        });
+        thread::sleep(Duration::from_millis(10));
+        token.cancel();
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(err.is::<Cancelled>());
+
+        Ok(())
+    }
+    #[test]
+    fn test_retry_with_report() -> Result<()> {
+        let error_times = Mutex::new(0);
+        let f = || {
+            let mut x = error_times.lock().unwrap();
+            *x += 1;
+            Err::<(), std::io::Error>(std::io::Error::other(format!("error {x}")))
+        };
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let err = f.retry(&backoff).call_with_report().unwrap_err();
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!(4, err.attempts());
+        assert_eq!("error 4", err.last_error().to_string());
+        let errors: Vec<_> = err.errors().iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["error 1", "error 2", "error 3"], errors);
+        Ok(())
+    }
+    #[test]
+    fn test_retry_with_fallback() -> Result<()> {
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let result = always_error
+            .retry(&backoff)
+            .fallback(|err: RetryError<anyhow::Error>| {
+                assert_eq!(4, err.attempts());
+                Ok(())
+            })
+            .call();
+        assert!(result.is_ok());
+
+        let f = || Err::<&str, anyhow::Error>(anyhow::anyhow!("not retryable"));
+        let result = f
+            .retry(&backoff)
+            .when(|_| false)
+            .fallback_value("stale")
+            .call();
+        assert_eq!("stale", result?);
+        Ok(())
+    }
+    #[test]
+    fn test_retry_with_test_clock() -> Result<()> {
+        let clock = TestClock::new();
+        let err = always_error
+            .retry(&ExponentialBuilder::default())
+            .sleep(clock.clone())
+            .call_with_report()
+            .unwrap_err();
+        clock.assert_sleeps(&[
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            Duration::from_secs(4),
+        ]);
+        assert_eq!(Duration::from_secs(7), clock.elapsed());
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!(Duration::from_secs(7), err.elapsed());
+        Ok(())
+    }
+    #[test]
+    fn test_retry_with_deadline() -> Result<()> {
+        let backoff = ExponentialBuilder::default()
+            .with_min_delay(Duration::from_millis(10))
+            .with_max_times(10)
+            .with_deadline(web_time::Instant::now() + Duration::from_millis(50));
+        let err = always_error.retry(&backoff).call_with_report().unwrap_err();
+        assert_eq!(StopReason::Deadline, err.reason());
+        assert_eq!(err.attempts(), err.errors().len() + 1);
+        Ok(())
+    }
+    #[test]
+    fn test_retry_with_decide() -> Result<()> {
+        let clock = TestClock::new();
+        let error_times = Mutex::new(0);
+        let f = || {
+            let mut x = error_times.lock().unwrap();
+            *x += 1;
+            Err::<(), std::io::Error>(std::io::Error::other(format!("retry after {x}")))
+        };
+        let backoff = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_total_delay(Duration::from_secs(7));
+        let err = f
+            .retry(&backoff)
+            .sleep(clock.clone())
+            .decide(|e| {
+                let secs: u64 = e.to_string()["retry after ".len()..].parse().unwrap();
+                RetryDecision::RetryAfter(Duration::from_secs(secs))
+            })
+            .call_with_report()
+            .unwrap_err();
+        // the last override is clipped to fit the total delay of the builder
+        clock.assert_sleeps(&[
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            Duration::from_secs(3),
+            Duration::from_secs(1),
+        ]);
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!("retry after 5", err.last_error().to_string());
+
+        let err = always_error
+            .retry(&ExponentialBuilder::default())
+            .sleep(clock.clone())
+            .decide(|_| RetryDecision::Stop)
+            .call_with_report()
+            .unwrap_err();
+        assert_eq!(StopReason::NotRetryable, err.reason());
+        assert_eq!(1, err.attempts());
+        Ok(())
+    }
 }
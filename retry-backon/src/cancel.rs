@@ -0,0 +1,91 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use web_time::Instant;
+
+/// CancellationToken is used to stop `Retry` and `BlockingRetry` promptly,
+/// e.g. during a graceful shutdown. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: bool,
+    wakers: Vec<Waker>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Cancel the token, waking up every retry waiting on it.
+    pub fn cancel(&self) {
+        let wakers = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.cancelled = true;
+            std::mem::take(&mut state.wakers)
+        };
+        self.inner.condvar.notify_all();
+        wakers.into_iter().for_each(Waker::wake);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.state.lock().unwrap().cancelled
+    }
+    /// Returns ready once the token is cancelled, otherwise the task is woken up on cancel.
+    pub(crate) fn poll_cancelled(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.cancelled {
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+    /// Block the current thread for `dur`, returns true if cancelled meanwhile.
+    pub(crate) fn wait_timeout(&self, dur: Duration) -> bool {
+        let deadline = Instant::now().checked_add(dur);
+        let mut state = self.inner.state.lock().unwrap();
+        while !state.cancelled {
+            let remaining = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => Duration::MAX,
+            };
+            if remaining.is_zero() {
+                break;
+            }
+            state = self.inner.condvar.wait_timeout(state, remaining).unwrap().0;
+        }
+        state.cancelled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_wait_timeout() {
+        let token = CancellationToken::new();
+        assert!(!token.wait_timeout(Duration::from_millis(1)));
+
+        let cloned = token.clone();
+        let handle = thread::spawn(move || cloned.wait_timeout(Duration::from_secs(60)));
+        token.cancel();
+        assert!(handle.join().unwrap());
+        assert!(token.is_cancelled());
+    }
+}
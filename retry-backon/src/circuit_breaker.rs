@@ -0,0 +1,434 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use web_time::Instant;
+
+use crate::{
+    backoff::{Backoff, BackoffBuilder, DynBackoffBuilder},
+    error::CircuitOpen,
+};
+
+/// CircuitState is the state of a `CircuitBreaker`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Attempts go through, their outcomes are recorded.
+    Closed,
+    /// Attempts are rejected until the cool-down ends.
+    Open,
+    /// A single probe attempt is let through, its outcome closes or opens the circuit again.
+    HalfOpen,
+}
+
+/// CircuitBreaker rejects attempts while a dependency is clearly down.
+///
+/// It opens once the failure rate of the last `window` attempts reaches
+/// `failure_rate`, then waits for a cool-down taken from a backoff
+/// before letting a probe through. Each failed probe waits for the next
+/// delay of the backoff, which starts over once the circuit closes.
+/// It's given to each retry by `Retry::with_circuit_breaker`, clones share the same state.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    inner: Arc<Mutex<BreakerState>>,
+}
+
+struct BreakerState {
+    window: usize,
+    min_calls: usize,
+    failure_rate: f64,
+    builder: DynBackoffBuilder,
+
+    state: CircuitState,
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    cooldown: Box<dyn Backoff>,
+    last_cooldown: Duration,
+    open_until: Option<Instant>,
+}
+
+impl fmt::Debug for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BreakerState")
+            .field("window", &self.window)
+            .field("min_calls", &self.min_calls)
+            .field("failure_rate", &self.failure_rate)
+            .field("state", &self.state)
+            .field("failures", &self.failures)
+            .field("open_until", &self.open_until)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CircuitBreaker {
+    /// Create a closed circuit breaker whose cool-downs are the delays of `cooldown`.
+    pub fn new<B>(cooldown: B) -> Self
+    where
+        B: BackoffBuilder + 'static,
+        B::Backoff: 'static,
+    {
+        let builder = DynBackoffBuilder::new(cooldown);
+        Self {
+            inner: Arc::new(Mutex::new(BreakerState {
+                window: 20,
+                min_calls: 10,
+                failure_rate: 0.5,
+                cooldown: builder.build(),
+                builder,
+
+                state: CircuitState::Closed,
+                outcomes: VecDeque::new(),
+                failures: 0,
+                last_cooldown: Duration::ZERO,
+                open_until: None,
+            })),
+        }
+    }
+    /// Compute the failure rate on the last `window` attempts, 20 by default.
+    pub fn with_window(self, window: usize) -> Self {
+        debug_assert!(window > 0, "window must hold at least one attempt");
+        self.inner.lock().unwrap().window = window;
+        self
+    }
+    /// Don't open before `min_calls` attempts are recorded in the window, 10 by default.
+    pub fn with_min_calls(self, min_calls: usize) -> Self {
+        self.inner.lock().unwrap().min_calls = min_calls;
+        self
+    }
+    /// Open once the failure rate reaches `failure_rate`, 0.5 by default.
+    pub fn with_failure_rate(self, failure_rate: f64) -> Self {
+        debug_assert!(
+            (0.0..=1.0).contains(&failure_rate),
+            "failure rate must be in [0, 1]"
+        );
+        self.inner.lock().unwrap().failure_rate = failure_rate;
+        self
+    }
+    /// The state at `now`, an open circuit whose cool-down is over is half-open.
+    /// Retries pass the time of their sleeper, so a `TestClock` drives the cool-downs.
+    pub fn state(&self, now: Instant) -> CircuitState {
+        let state = self.inner.lock().unwrap();
+        match state.open_until {
+            Some(until) if now >= until => CircuitState::HalfOpen,
+            _ => state.state,
+        }
+    }
+
+    /// Let an attempt through, or tell how long the circuit stays open.
+    pub(crate) fn try_acquire(&self, now: Instant) -> Result<CircuitPermit, CircuitOpen> {
+        let mut state = self.inner.lock().unwrap();
+        match state.state {
+            CircuitState::Closed => {}
+            CircuitState::Open => {
+                let until = state
+                    .open_until
+                    .expect("open circuit must have a cool-down");
+                if now < until {
+                    return Err(CircuitOpen(until - now));
+                }
+                state.state = CircuitState::HalfOpen;
+                state.open_until = None;
+                return Ok(CircuitPermit {
+                    breaker: self.clone(),
+                    probe: true,
+                    acquired: now,
+                });
+            }
+            // a probe is already in flight
+            CircuitState::HalfOpen => return Err(CircuitOpen(Duration::ZERO)),
+        }
+        Ok(CircuitPermit {
+            breaker: self.clone(),
+            probe: false,
+            acquired: now,
+        })
+    }
+}
+
+impl BreakerState {
+    fn open(&mut self, now: Instant) {
+        if let Some(cooldown) = self.cooldown.next_at(now) {
+            self.last_cooldown = cooldown;
+        }
+        self.state = CircuitState::Open;
+        self.open_until = Some(now + self.last_cooldown);
+        self.outcomes.clear();
+        self.failures = 0;
+    }
+
+    fn close(&mut self) {
+        self.state = CircuitState::Closed;
+        self.cooldown = self.builder.build();
+        self.last_cooldown = Duration::ZERO;
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        self.outcomes.push_back(success);
+        if !success {
+            self.failures += 1;
+        }
+        if self.outcomes.len() > self.window && self.outcomes.pop_front() == Some(false) {
+            self.failures -= 1;
+        }
+        let calls = self.outcomes.len();
+        if calls >= self.min_calls && self.failures as f64 >= self.failure_rate * calls as f64 {
+            self.open(now);
+        }
+    }
+}
+
+/// CircuitPermit is held by an attempt let through by a `CircuitBreaker`.
+/// A probe dropped without an outcome lets another probe through.
+#[derive(Debug)]
+pub(crate) struct CircuitPermit {
+    breaker: CircuitBreaker,
+    probe: bool,
+    acquired: Instant,
+}
+
+impl CircuitPermit {
+    /// Record the outcome of the attempt, finished at `now`.
+    pub(crate) fn record(mut self, success: bool, now: Instant) {
+        let mut state = self.breaker.inner.lock().unwrap();
+        match (self.probe, success) {
+            (true, true) => state.close(),
+            (true, false) => state.open(now),
+            (false, _) => {
+                // outcomes of attempts started before the circuit opened are dropped
+                if state.state == CircuitState::Closed {
+                    state.record(success, now)
+                }
+            }
+        }
+        drop(state);
+        self.probe = false;
+    }
+}
+
+impl Drop for CircuitPermit {
+    fn drop(&mut self) {
+        if self.probe {
+            let mut state = self.breaker.inner.lock().unwrap();
+            if state.state == CircuitState::HalfOpen {
+                // the cool-down is already over, the next attempt is a probe again
+                state.state = CircuitState::Open;
+                state.open_until = Some(self.acquired);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{
+        constant::ConstantBuilder,
+        error::CircuitOpen,
+        error::RetryDecision,
+        exponential::ExponentialBuilder,
+        limiter::RetryLimiter,
+        retry::Retryable,
+        sleep::{Sleeper, TestClock},
+    };
+
+    use super::*;
+
+    fn breaker(cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(ConstantBuilder::default().with_delay(cooldown))
+            .with_window(4)
+            .with_min_calls(4)
+    }
+
+    fn now(clock: &TestClock) -> Instant {
+        Sleeper::now(clock)
+    }
+
+    #[test]
+    fn test_circuit_breaker_opens() {
+        let clock = TestClock::new();
+        let cb = breaker(Duration::from_secs(60));
+        for success in [true, false, true] {
+            cb.try_acquire(now(&clock))
+                .unwrap()
+                .record(success, now(&clock));
+        }
+        assert_eq!(CircuitState::Closed, cb.state(now(&clock)));
+        cb.try_acquire(now(&clock))
+            .unwrap()
+            .record(false, now(&clock));
+        assert_eq!(CircuitState::Open, cb.state(now(&clock)));
+
+        clock.advance(Duration::from_secs(15));
+        let open = cb.try_acquire(now(&clock)).unwrap_err();
+        assert_eq!(Duration::from_secs(45), open.0);
+    }
+    #[test]
+    fn test_circuit_breaker_sliding_window() {
+        let clock = TestClock::new();
+        let cb = breaker(Duration::from_secs(60));
+        // old failures leave the window
+        for success in [false, true, true, true, true, false] {
+            cb.try_acquire(now(&clock))
+                .unwrap()
+                .record(success, now(&clock));
+        }
+        assert_eq!(CircuitState::Closed, cb.state(now(&clock)));
+        cb.try_acquire(now(&clock))
+            .unwrap()
+            .record(false, now(&clock));
+        assert_eq!(CircuitState::Open, cb.state(now(&clock)));
+    }
+    #[test]
+    fn test_circuit_breaker_half_open() {
+        let clock = TestClock::new();
+        let cb = CircuitBreaker::new(
+            ExponentialBuilder::default()
+                .with_min_delay(Duration::from_secs(10))
+                .with_max_times(10),
+        )
+        .with_window(1)
+        .with_min_calls(1);
+        cb.try_acquire(now(&clock))
+            .unwrap()
+            .record(false, now(&clock));
+        assert_eq!(CircuitState::Open, cb.state(now(&clock)));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(CircuitState::HalfOpen, cb.state(now(&clock)));
+        let probe = cb.try_acquire(now(&clock)).unwrap();
+        // only one probe at a time
+        assert!(cb.try_acquire(now(&clock)).is_err());
+        probe.record(false, now(&clock));
+        // the next cool-down is longer
+        let open = cb.try_acquire(now(&clock)).unwrap_err();
+        assert_eq!(Duration::from_secs(20), open.0);
+
+        clock.advance(Duration::from_secs(20));
+        cb.try_acquire(now(&clock))
+            .unwrap()
+            .record(true, now(&clock));
+        assert_eq!(CircuitState::Closed, cb.state(now(&clock)));
+    }
+    #[test]
+    fn test_circuit_breaker_dropped_probe() {
+        let clock = TestClock::new();
+        let cb = breaker(Duration::from_secs(10))
+            .with_window(1)
+            .with_min_calls(1);
+        cb.try_acquire(now(&clock))
+            .unwrap()
+            .record(false, now(&clock));
+        clock.advance(Duration::from_secs(10));
+        drop(cb.try_acquire(now(&clock)).unwrap());
+        assert!(cb.try_acquire(now(&clock)).is_ok());
+    }
+
+    #[derive(Debug)]
+    enum Error {
+        Unavailable,
+        Open(CircuitOpen),
+    }
+
+    impl From<CircuitOpen> for Error {
+        fn from(open: CircuitOpen) -> Self {
+            Error::Open(open)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_circuit_breaker() -> Result<()> {
+        let cb = breaker(Duration::from_secs(60))
+            .with_window(2)
+            .with_min_calls(2);
+        let mut calls = 0;
+        let clock = TestClock::new();
+        let result = (|| {
+            calls += 1;
+            async { Err::<(), _>(Error::Unavailable) }
+        })
+        .retry(&ExponentialBuilder::default())
+        .sleep(clock.clone())
+        .with_circuit_breaker(cb.clone())
+        .notify(|err, _| {
+            if let Error::Open(open) = err {
+                assert!(open.0 > Duration::from_secs(50));
+            }
+        })
+        .with_report()
+        .await;
+        let err = result.unwrap_err();
+        assert!(matches!(err.last_error(), Error::Open(_)));
+        // the circuit opened after two calls, the later attempts were rejected and not counted
+        assert_eq!(2, calls);
+        assert_eq!(2, err.attempts());
+        assert_eq!(CircuitState::Open, cb.state(now(&clock)));
+        assert_eq!(3, clock.sleeps().len());
+
+        let result = (|| async { Ok::<_, Error>(()) })
+            .retry(&ExponentialBuilder::default())
+            .sleep(clock.clone())
+            .with_circuit_breaker(cb.clone())
+            .when(|e| !matches!(e, Error::Open(_)))
+            .await;
+        assert!(matches!(result, Err(Error::Open(_))));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_waits_out_the_cool_down() {
+        let cb = breaker(Duration::from_secs(60))
+            .with_window(1)
+            .with_min_calls(1);
+        let clock = TestClock::new();
+        let mut calls = 0;
+        let result = (|| {
+            calls += 1;
+            let n = calls;
+            async move {
+                match n {
+                    1 => Err(Error::Unavailable),
+                    _ => Ok(n),
+                }
+            }
+        })
+        .retry(&ConstantBuilder::default().with_delay(Duration::from_secs(1)))
+        .sleep(clock.clone())
+        .with_circuit_breaker(cb.clone())
+        .decide(|err| match err {
+            Error::Open(open) => RetryDecision::RetryAfter(open.0),
+            Error::Unavailable => RetryDecision::Retry,
+        })
+        .await;
+        assert!(matches!(result, Ok(2)));
+        // the attempt after the failure is rejected, then the probe runs once the cool-down is over
+        clock.assert_sleeps(&[Duration::from_secs(1), Duration::from_secs(59)]);
+        assert_eq!(CircuitState::Closed, cb.state(now(&clock)));
+    }
+    #[tokio::test]
+    async fn test_retry_with_circuit_breaker_and_limiter() -> Result<()> {
+        let cb = breaker(Duration::from_secs(60))
+            .with_window(1)
+            .with_min_calls(1);
+        let limiter = RetryLimiter::new().with_max_in_flight(1);
+        let mut calls = 0;
+        let retry = (|| {
+            calls += 1;
+            async { Err::<(), _>(Error::Unavailable) }
+        })
+        .retry(&ExponentialBuilder::default())
+        .sleep(TestClock::new())
+        .with_circuit_breaker(cb.clone())
+        .with_limiter(limiter.clone());
+        // a rejected attempt gives its permit back instead of blocking the next retry
+        let result = tokio::time::timeout(Duration::from_secs(5), retry).await?;
+        assert!(matches!(result, Err(Error::Open(_))));
+        assert_eq!(1, calls);
+        assert_eq!(0, limiter.in_flight());
+        assert_eq!(0, limiter.waiting());
+
+        Ok(())
+    }
+}
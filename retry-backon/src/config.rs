@@ -0,0 +1,349 @@
+use std::{fmt, time::Duration};
+
+use serde::Deserialize;
+
+use crate::{
+    backoff::DynBackoffBuilder,
+    constant::ConstantBuilder,
+    decorrelated_jitter::DecorrelatedJitterBuilder,
+    exponential::{ExponentialBuilder, Jitter},
+    fibonacci::FibonacciBuilder,
+    linear::LinearBuilder,
+};
+
+/// BackoffConfig describes a backoff in a config file, the kind is picked by the `kind` field.
+///
+/// Durations are written with a unit, like `"500ms"`, `"1.5s"`, `"2m"` or `"1h"`,
+/// and missing fields take the defaults of the matching builder.
+///
+/// ```toml
+/// kind = "exponential"
+/// min_delay = "100ms"
+/// max_delay = "10s"
+/// max_times = 5
+/// jitter = "full"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum BackoffConfig {
+    Constant(ConstantConfig),
+    Exponential(ExponentialConfig),
+    Fibonacci(FibonacciConfig),
+    Linear(LinearConfig),
+    DecorrelatedJitter(DecorrelatedJitterConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConstantConfig {
+    #[serde(with = "duration")]
+    pub delay: Duration,
+    pub max_times: usize,
+}
+
+impl Default for ConstantConfig {
+    fn default() -> Self {
+        Self {
+            delay: Duration::from_secs(1),
+            max_times: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExponentialConfig {
+    #[serde(with = "duration")]
+    pub min_delay: Duration,
+    #[serde(with = "duration")]
+    pub max_delay: Duration,
+    pub factor: f32,
+    pub max_times: usize,
+    pub jitter: Jitter,
+}
+
+impl Default for ExponentialConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            factor: 2.0,
+            max_times: 3,
+            jitter: Jitter::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FibonacciConfig {
+    #[serde(with = "duration")]
+    pub min_delay: Duration,
+    #[serde(with = "duration")]
+    pub max_delay: Duration,
+    pub max_times: usize,
+}
+
+impl Default for FibonacciConfig {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_times: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinearConfig {
+    #[serde(with = "duration")]
+    pub step: Duration,
+    #[serde(with = "duration")]
+    pub min_delay: Duration,
+    #[serde(with = "duration")]
+    pub max_delay: Duration,
+    pub max_times: usize,
+}
+
+impl Default for LinearConfig {
+    fn default() -> Self {
+        Self {
+            step: Duration::from_secs(1),
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_times: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecorrelatedJitterConfig {
+    #[serde(with = "duration")]
+    pub base_delay: Duration,
+    #[serde(with = "duration")]
+    pub max_delay: Duration,
+    pub max_times: usize,
+}
+
+impl Default for DecorrelatedJitterConfig {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_times: 3,
+        }
+    }
+}
+
+/// ConfigError tells why a `BackoffConfig` can't be built.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The exponential factor must be a finite number greater than 1.
+    InvalidFactor(f32),
+    /// The first delay is greater than the maximum delay.
+    DelayAboveMax {
+        delay: Duration,
+        max_delay: Duration,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidFactor(factor) => {
+                write!(f, "factor must be greater than 1, got {factor}")
+            }
+            ConfigError::DelayAboveMax { delay, max_delay } => write!(
+                f,
+                "first delay {delay:?} is greater than max_delay {max_delay:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn check_max(delay: Duration, max_delay: Duration) -> Result<(), ConfigError> {
+    if delay > max_delay {
+        return Err(ConfigError::DelayAboveMax { delay, max_delay });
+    }
+    Ok(())
+}
+
+impl BackoffConfig {
+    /// Check the config without building it.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self {
+            BackoffConfig::Constant(_) => Ok(()),
+            BackoffConfig::Exponential(c) => {
+                if !c.factor.is_finite() || c.factor <= 1.0 {
+                    return Err(ConfigError::InvalidFactor(c.factor));
+                }
+                check_max(c.min_delay, c.max_delay)
+            }
+            BackoffConfig::Fibonacci(c) => check_max(c.min_delay, c.max_delay),
+            BackoffConfig::Linear(c) => check_max(c.min_delay, c.max_delay),
+            BackoffConfig::DecorrelatedJitter(c) => check_max(c.base_delay, c.max_delay),
+        }
+    }
+
+    /// Validate the config and build the matching backoff builder.
+    pub fn build(&self) -> Result<DynBackoffBuilder, ConfigError> {
+        self.validate()?;
+        let builder = match self.clone() {
+            BackoffConfig::Constant(c) => DynBackoffBuilder::new(
+                ConstantBuilder::default()
+                    .with_delay(c.delay)
+                    .with_max_times(c.max_times),
+            ),
+            BackoffConfig::Exponential(c) => DynBackoffBuilder::new(
+                ExponentialBuilder::default()
+                    .with_min_delay(c.min_delay)
+                    .with_max_delay(c.max_delay)
+                    .with_factor(c.factor)
+                    .with_max_times(c.max_times)
+                    .with_jitter_mode(c.jitter),
+            ),
+            BackoffConfig::Fibonacci(c) => DynBackoffBuilder::new(
+                FibonacciBuilder::default()
+                    .with_min_delay(c.min_delay)
+                    .with_max_delay(c.max_delay)
+                    .with_max_times(c.max_times),
+            ),
+            BackoffConfig::Linear(c) => DynBackoffBuilder::new(
+                LinearBuilder::default()
+                    .with_step(c.step)
+                    .with_min_delay(c.min_delay)
+                    .with_max_delay(c.max_delay)
+                    .with_max_times(c.max_times),
+            ),
+            BackoffConfig::DecorrelatedJitter(c) => DynBackoffBuilder::new(
+                DecorrelatedJitterBuilder::default()
+                    .with_base_delay(c.base_delay)
+                    .with_max_delay(c.max_delay)
+                    .with_max_times(c.max_times),
+            ),
+        };
+        Ok(builder)
+    }
+}
+
+impl TryFrom<BackoffConfig> for DynBackoffBuilder {
+    type Error = ConfigError;
+    fn try_from(config: BackoffConfig) -> Result<Self, Self::Error> {
+        config.build()
+    }
+}
+
+/// Deserialize a duration written as a number followed by `ms`, `s`, `m` or `h`.
+mod duration {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    use crate::duration::parse_duration;
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let s = String::deserialize(d)?;
+        parse_duration(&s).ok_or_else(|| {
+            D::Error::custom(format!(
+                "invalid duration {s:?}, expected a number followed by ms, s, m or h"
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{backoff::BackoffBuilder, duration::parse_duration, retry::Retryable};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Some(Duration::from_millis(500)), parse_duration("500ms"));
+        assert_eq!(Some(Duration::from_millis(1500)), parse_duration("1.5s"));
+        assert_eq!(Some(Duration::from_secs(120)), parse_duration("2m"));
+        assert_eq!(Some(Duration::from_secs(3600)), parse_duration("1h"));
+        assert_eq!(None, parse_duration("10"));
+        assert_eq!(None, parse_duration("-1s"));
+        assert_eq!(None, parse_duration("1d"));
+    }
+    #[test]
+    fn test_config_from_toml() -> Result<()> {
+        let config: BackoffConfig = toml::from_str(
+            r#"
+            kind = "exponential"
+            min_delay = "100ms"
+            max_delay = "1s"
+            max_times = 5
+            "#,
+        )?;
+        let delays: Vec<_> = config.build()?.build().collect();
+        assert_eq!(
+            vec![100, 200, 400, 800, 1000]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect::<Vec<_>>(),
+            delays
+        );
+
+        let config: BackoffConfig = toml::from_str(r#"kind = "constant""#)?;
+        assert_eq!(BackoffConfig::Constant(ConstantConfig::default()), config);
+        Ok(())
+    }
+    #[test]
+    fn test_config_rejected() {
+        let err = toml::from_str::<BackoffConfig>(r#"kind = "random""#).unwrap_err();
+        assert!(err.to_string().contains("unknown variant"), "{err}");
+
+        let err = toml::from_str::<BackoffConfig>("kind = \"constant\"\ndelay = \"1 second\"")
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid duration"), "{err}");
+
+        let err =
+            toml::from_str::<BackoffConfig>("kind = \"constant\"\nmin_delay = \"1s\"").unwrap_err();
+        assert!(err.to_string().contains("unknown field"), "{err}");
+
+        let config: BackoffConfig = toml::from_str("kind = \"exponential\"\nfactor = 0.5").unwrap();
+        assert_eq!(
+            "factor must be greater than 1, got 0.5",
+            config.build().unwrap_err().to_string()
+        );
+
+        let config: BackoffConfig =
+            toml::from_str("kind = \"linear\"\nmin_delay = \"2m\"\nmax_delay = \"1m\"").unwrap();
+        assert_eq!(
+            Err(ConfigError::DelayAboveMax {
+                delay: Duration::from_secs(120),
+                max_delay: Duration::from_secs(60)
+            }),
+            config.validate()
+        );
+    }
+    #[tokio::test]
+    async fn test_retry_with_config() -> Result<()> {
+        let config: BackoffConfig = toml::from_str(
+            r#"
+            kind = "fibonacci"
+            min_delay = "1ms"
+            max_times = 2
+            "#,
+        )?;
+        let backoff = config.build()?;
+        let mut calls = 0;
+        let result = (|| {
+            calls += 1;
+            async { Err::<(), _>(anyhow::anyhow!("retryable")) }
+        })
+        .retry(&backoff)
+        .await;
+        assert!(result.is_err());
+        assert_eq!(3, calls);
+        Ok(())
+    }
+}
@@ -1,10 +1,13 @@
 use std::time::Duration;
 
-use crate::backoff::BackoffBuilder;
+use web_time::Instant;
+
+use crate::backoff::{Backoff, BackoffBuilder, BackoffState, DelayLimit, ResumableBuilder};
 #[derive(Debug, Clone)]
 pub struct ConstantBuilder {
     dealy: Duration,
     max_times: Option<usize>,
+    limit: DelayLimit,
 }
 
 impl Default for ConstantBuilder {
@@ -12,6 +15,7 @@ impl Default for ConstantBuilder {
         Self {
             dealy: Duration::from_secs(1),
             max_times: Some(3),
+            limit: DelayLimit::default(),
         }
     }
 }
@@ -26,6 +30,18 @@ impl ConstantBuilder {
         self.max_times = Some(max_times);
         self
     }
+    /// Stop the backoff once the sum of delays would exceed `total_delay`,
+    /// the last delay is clipped to fit.
+    pub fn with_total_delay(mut self, total_delay: Duration) -> Self {
+        self.limit.total_delay = Some(total_delay);
+        self
+    }
+    /// Stop the backoff once a delay would end after `deadline`,
+    /// the last delay is clipped to fit.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.limit.deadline = Some(deadline);
+        self
+    }
 }
 
 impl BackoffBuilder for ConstantBuilder {
@@ -34,14 +50,33 @@ impl BackoffBuilder for ConstantBuilder {
         ConstantBackoff {
             dealy: self.dealy,
             max_times: self.max_times,
+            limit: self.limit.clone(),
             attempts: 0,
         }
     }
 }
 
+impl ResumableBuilder for ConstantBuilder {
+    fn resume(&self, state: &BackoffState) -> ConstantBackoff {
+        let mut backoff = self.build();
+        backoff.attempts = state.attempts;
+        backoff.limit.resume(state.slept);
+        backoff
+    }
+    fn state(backoff: &ConstantBackoff) -> BackoffState {
+        BackoffState {
+            attempts: backoff.attempts,
+            current_delay: (backoff.attempts > 0).then_some(backoff.dealy),
+            slept: backoff.limit.slept(),
+            last_failure: None,
+        }
+    }
+}
+
 pub struct ConstantBackoff {
     dealy: Duration,
     max_times: Option<usize>,
+    limit: DelayLimit,
 
     attempts: usize,
 }
@@ -51,14 +86,14 @@ impl Default for ConstantBackoff {
         Self {
             dealy: Duration::from_secs(1),
             max_times: Some(3),
+            limit: DelayLimit::default(),
             attempts: 0,
         }
     }
 }
 
-impl Iterator for ConstantBackoff {
-    type Item = Duration;
-    fn next(&mut self) -> Option<Self::Item> {
+impl ConstantBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
         match self.max_times {
             None => Some(self.dealy),
             Some(max_times) => {
@@ -72,10 +107,33 @@ impl Iterator for ConstantBackoff {
         }
     }
 }
+
+impl Iterator for ConstantBackoff {
+    type Item = Duration;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(Instant::now())
+    }
+}
+
+impl Backoff for ConstantBackoff {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let delay = self.next_delay();
+        self.limit.clip(now, delay)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        self.next_delay()?;
+        self.limit.clip(now, Some(delay))
+    }
+    fn deadline_reached(&self) -> bool {
+        self.limit.deadline_reached()
+    }
+}
 #[cfg(test)]
 mod tests {
     use std::time::Duration;
 
+    use web_time::Instant;
+
     use crate::backoff::BackoffBuilder;
     use crate::constant::ConstantBuilder;
 
@@ -108,4 +166,25 @@ mod tests {
         assert_eq!(Some(Duration::from_secs(1)), exp.next());
         assert_eq!(None, exp.next());
     }
+
+    #[test]
+    fn test_constant_with_total_delay() {
+        let mut exp = ConstantBuilder::default()
+            .with_total_delay(Duration::from_millis(2500))
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        assert_eq!(Some(Duration::from_millis(500)), exp.next());
+        assert_eq!(None, exp.next());
+    }
+
+    #[test]
+    fn test_constant_with_deadline() {
+        let mut exp = ConstantBuilder::default()
+            .with_deadline(Instant::now())
+            .build();
+
+        assert_eq!(None, exp.next());
+    }
 }
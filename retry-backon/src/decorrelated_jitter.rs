@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use rand::Rng;
+use web_time::Instant;
+
+use crate::backoff::{Backoff, BackoffBuilder, BackoffState, DelayLimit, ResumableBuilder};
+/// DecorrelatedJitterBuilder builds a backoff whose delay is a random value
+/// in `[base_delay, previous * 3]`, capped by `max_delay`.
+#[derive(Debug, Clone)]
+pub struct DecorrelatedJitterBuilder {
+    base_delay: Duration,
+    max_delay: Option<Duration>,
+    max_times: Option<usize>,
+    limit: DelayLimit,
+}
+
+impl Default for DecorrelatedJitterBuilder {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Some(Duration::from_secs(60)),
+            max_times: Some(3),
+            limit: DelayLimit::default(),
+        }
+    }
+}
+
+impl DecorrelatedJitterBuilder {
+    pub fn with_base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+    pub fn with_max_times(mut self, max_times: usize) -> Self {
+        self.max_times = Some(max_times);
+        self
+    }
+    /// Stop the backoff once the sum of delays would exceed `total_delay`,
+    /// the last delay is clipped to fit.
+    pub fn with_total_delay(mut self, total_delay: Duration) -> Self {
+        self.limit.total_delay = Some(total_delay);
+        self
+    }
+    /// Stop the backoff once a delay would end after `deadline`,
+    /// the last delay is clipped to fit.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.limit.deadline = Some(deadline);
+        self
+    }
+}
+
+impl BackoffBuilder for DecorrelatedJitterBuilder {
+    type Backoff = DecorrelatedJitterBackoff;
+    fn build(&self) -> Self::Backoff {
+        DecorrelatedJitterBackoff {
+            base_delay: self.base_delay,
+            max_delay: self.max_delay,
+            max_times: self.max_times,
+            limit: self.limit.clone(),
+
+            current_delay: None,
+            attempts: 0,
+        }
+    }
+}
+
+impl ResumableBuilder for DecorrelatedJitterBuilder {
+    fn resume(&self, state: &BackoffState) -> DecorrelatedJitterBackoff {
+        let mut backoff = self.build();
+        backoff.attempts = state.attempts;
+        backoff.current_delay = state.current_delay;
+        backoff.limit.resume(state.slept);
+        backoff
+    }
+    fn state(backoff: &DecorrelatedJitterBackoff) -> BackoffState {
+        BackoffState {
+            attempts: backoff.attempts,
+            current_delay: backoff.current_delay,
+            slept: backoff.limit.slept(),
+            last_failure: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DecorrelatedJitterBackoff {
+    base_delay: Duration,
+    max_delay: Option<Duration>,
+    max_times: Option<usize>,
+    limit: DelayLimit,
+
+    current_delay: Option<Duration>,
+    attempts: usize,
+}
+
+impl DecorrelatedJitterBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempts >= self.max_times.unwrap_or(usize::MAX) {
+            return None;
+        }
+        self.attempts += 1;
+        let prev = self.current_delay.unwrap_or(self.base_delay);
+        let upper = prev.saturating_mul(3);
+        let cur = if upper > self.base_delay {
+            rand::thread_rng().gen_range(self.base_delay..=upper)
+        } else {
+            self.base_delay
+        };
+        let cur = match self.max_delay {
+            Some(max_delay) => cur.min(max_delay),
+            None => cur,
+        };
+        self.current_delay = Some(cur);
+        Some(cur)
+    }
+}
+
+impl Iterator for DecorrelatedJitterBackoff {
+    type Item = Duration;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(Instant::now())
+    }
+}
+
+impl Backoff for DecorrelatedJitterBackoff {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let delay = self.next_delay();
+        self.limit.clip(now, delay)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        self.next_delay()?;
+        self.limit.clip(now, Some(delay))
+    }
+    fn deadline_reached(&self) -> bool {
+        self.limit.deadline_reached()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_decorrelated_jitter_default() {
+        let mut dj = DecorrelatedJitterBuilder::default().build();
+
+        let mut prev = Duration::from_secs(1);
+        for _ in 0..3 {
+            let v = dj.next().expect("value must valid");
+            assert!(v >= Duration::from_secs(1), "current: {v:?}");
+            assert!(v <= prev * 3, "current: {v:?}");
+            prev = v;
+        }
+        assert_eq!(None, dj.next());
+    }
+    #[test]
+    fn test_decorrelated_jitter_max_delay() {
+        let mut dj = DecorrelatedJitterBuilder::default()
+            .with_max_times(10)
+            .with_max_delay(Duration::from_secs(2))
+            .build();
+
+        for _ in 0..10 {
+            let v = dj.next().expect("value must valid");
+            assert!(v >= Duration::from_secs(1), "current: {v:?}");
+            assert!(v <= Duration::from_secs(2), "current: {v:?}");
+        }
+        assert_eq!(None, dj.next());
+    }
+    #[test]
+    fn test_decorrelated_jitter_max_times() {
+        let mut dj = DecorrelatedJitterBuilder::default()
+            .with_max_times(1)
+            .build();
+
+        assert!(dj.next().is_some());
+        assert_eq!(None, dj.next());
+    }
+}
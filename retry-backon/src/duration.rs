@@ -0,0 +1,21 @@
+//! The duration format of config files and of the `#[retry]` attribute in the
+//! macros crate, which includes this file so both accept the same strings.
+//! It must stay free of dependencies for that.
+
+use std::time::Duration;
+
+/// Parse a number followed by `ms`, `s`, `m` or `h`, like `"500ms"`, `"1.5s"`, `"2m"` or `"1h"`.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic())?;
+    let (value, unit) = s.split_at(split);
+    let value: f64 = value.trim().parse().ok()?;
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
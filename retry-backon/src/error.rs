@@ -0,0 +1,233 @@
+use std::{error::Error, fmt, time::Duration};
+
+use web_time::Instant;
+
+use crate::{backoff::Backoff, instrument::Instrument};
+
+/// AttemptTimeout is produced when a single attempt runs longer than
+/// the timeout set by `Retry::with_attempt_timeout`.
+/// It is converted into the operation's error type by `From`,
+/// so `when` and `notify` can tell it apart from the operation's own errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttemptTimeout(pub Duration);
+
+impl fmt::Display for AttemptTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "attempt timed out after {:?}", self.0)
+    }
+}
+
+impl Error for AttemptTimeout {}
+
+/// Converts an `AttemptTimeout` into the operation's error.
+pub(crate) type IntoTimeoutError<E> = fn(AttemptTimeout) -> E;
+
+/// Cancelled is produced when the `CancellationToken` of a retry is cancelled.
+/// It is converted into the operation's error type by `From`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "retry cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Converts a `Cancelled` into the operation's error.
+pub(crate) type IntoCancelledError<E> = fn(Cancelled) -> E;
+
+/// CircuitOpen is produced when a `CircuitBreaker` rejects an attempt,
+/// it holds how long the circuit stays open.
+/// It is converted into the operation's error type by `From`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitOpen(pub Duration);
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "circuit breaker is open for {:?}", self.0)
+    }
+}
+
+impl Error for CircuitOpen {}
+
+/// Converts a `CircuitOpen` into the operation's error.
+pub(crate) type IntoCircuitOpenError<E> = fn(CircuitOpen) -> E;
+
+/// RetryDecision is returned by the classifier set with `decide`,
+/// it tells what to do with an error accepted by `when`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetryDecision {
+    /// Retry after the next delay of the backoff.
+    #[default]
+    Retry,
+    /// Retry after the given delay instead, e.g. the one asked by a rate-limited API.
+    /// It still takes a step of the backoff, and is clipped by the total delay
+    /// and deadline of its builder like any other delay.
+    RetryAfter(Duration),
+    /// Give up at once.
+    Stop,
+}
+
+/// StopReason tells why a retry gave up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The error was rejected by `when` or `decide`.
+    NotRetryable,
+    /// The backoff has no delay left, or the total delay is spent.
+    Exhausted,
+    /// The backoff stopped at the deadline set on its builder.
+    Deadline,
+    /// The cancellation token was cancelled.
+    Cancelled,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::NotRetryable => write!(f, "error is not retryable"),
+            StopReason::Exhausted => write!(f, "backoff exhausted"),
+            StopReason::Deadline => write!(f, "deadline reached"),
+            StopReason::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// RetryError is returned by a retry in report mode,
+/// it keeps every error met and tells why the retry gave up.
+#[derive(Debug)]
+pub struct RetryError<E> {
+    last: E,
+    errors: Vec<E>,
+    attempts: usize,
+    elapsed: Duration,
+    reason: StopReason,
+}
+
+impl<E> RetryError<E> {
+    /// The error returned by the last attempt,
+    /// or the `Cancelled` error if the retry was cancelled between attempts.
+    pub fn last_error(&self) -> &E {
+        &self.last
+    }
+    /// The errors met before the last one, in order.
+    pub fn errors(&self) -> &[E] {
+        &self.errors
+    }
+    pub fn attempts(&self) -> usize {
+        self.attempts
+    }
+    /// Time elapsed since the first attempt started.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+    pub fn reason(&self) -> StopReason {
+        self.reason
+    }
+    pub fn into_inner(self) -> E {
+        self.last
+    }
+}
+
+impl<E> fmt::Display for RetryError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "retry gave up after {} attempts in {:?}: {}",
+            self.attempts, self.elapsed, self.reason
+        )
+    }
+}
+
+impl<E: Error + 'static> Error for RetryError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.last)
+    }
+}
+
+/// Report records the attempts of a retry to build its `RetryError`,
+/// errors are only kept when `collect` is set.
+#[derive(Debug)]
+pub(crate) struct Report<E> {
+    pub(crate) collect: bool,
+    pub(crate) instrument: Instrument,
+    attempts: usize,
+    started: Option<Instant>,
+    errors: Vec<E>,
+}
+
+impl<E> Report<E> {
+    pub(crate) fn new() -> Self {
+        Self {
+            collect: false,
+            instrument: Instrument::default(),
+            attempts: 0,
+            started: None,
+            errors: Vec::new(),
+        }
+    }
+    /// Start the clock of `RetryError::elapsed`, only the first call counts.
+    pub(crate) fn start(&mut self, now: Instant) {
+        self.started.get_or_insert(now);
+    }
+    /// Count an attempt which is actually run.
+    pub(crate) fn start_attempt(&mut self) {
+        self.attempts += 1;
+        self.instrument.attempt();
+    }
+    pub(crate) fn retry(&mut self, err: E, delay: Duration) {
+        self.instrument.retry(delay);
+        if self.collect {
+            self.errors.push(err);
+        }
+    }
+    pub(crate) fn succeed(&mut self) {
+        self.instrument.succeed();
+    }
+    /// The step shared by every retry loop once `when` accepted `err`:
+    /// ask `decide`, take the next delay of `backoff` and give up if the retry
+    /// is `cancelled` or the backoff is over. Returns the delay to sleep before
+    /// the next attempt, after passing it to `notify`.
+    pub(crate) fn next_delay<B: Backoff + ?Sized>(
+        &mut self,
+        err: E,
+        backoff: &mut B,
+        decide: fn(&E) -> RetryDecision,
+        notify: fn(&E, Duration),
+        cancelled: bool,
+        now: Instant,
+    ) -> Result<Duration, RetryError<E>> {
+        let next = match decide(&err) {
+            RetryDecision::Retry => backoff.next_at(now),
+            RetryDecision::RetryAfter(after) => backoff.next_after(now, after),
+            RetryDecision::Stop => return Err(self.give_up(err, StopReason::NotRetryable, now)),
+        };
+        if cancelled {
+            return Err(self.give_up(err, StopReason::Cancelled, now));
+        }
+        let Some(delay) = next else {
+            let reason = match backoff.deadline_reached() {
+                true => StopReason::Deadline,
+                false => StopReason::Exhausted,
+            };
+            return Err(self.give_up(err, reason, now));
+        };
+        notify(&err, delay);
+        self.retry(err, delay);
+        Ok(delay)
+    }
+    pub(crate) fn give_up(&mut self, last: E, reason: StopReason, now: Instant) -> RetryError<E> {
+        self.instrument.give_up(reason);
+        RetryError {
+            last,
+            errors: std::mem::take(&mut self.errors),
+            attempts: self.attempts,
+            elapsed: self
+                .started
+                .map(|t| now.saturating_duration_since(t))
+                .unwrap_or_default(),
+            reason,
+        }
+    }
+}
@@ -1,25 +1,50 @@
 use std::time::Duration;
 
-use rand::Rng;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use web_time::Instant;
+
+use crate::backoff::{Backoff, BackoffBuilder, BackoffState, DelayLimit, ResumableBuilder};
+
+/// Jitter decides how randomness is applied to the delay computed by ExponentialBackoff.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Jitter {
+    /// Use the computed delay as is.
+    #[default]
+    None,
+    /// Pick a random delay in `[0, delay]`.
+    Full,
+    /// Pick a random delay in `[delay / 2, delay]`.
+    Equal,
+    /// Add a random jitter in `[0, min_delay)` to the delay.
+    Additive,
+}
 
-use crate::backoff::BackoffBuilder;
 #[derive(Debug, Clone)]
 pub struct ExponentialBuilder {
-    jitter: bool,
+    jitter: Jitter,
+    seed: Option<u64>,
     factor: f32,
     min_delay: Duration,
     max_delay: Option<Duration>,
     max_times: Option<usize>,
+    limit: DelayLimit,
 }
 
 impl Default for ExponentialBuilder {
     fn default() -> Self {
         Self {
-            jitter: false,
+            jitter: Jitter::None,
+            seed: None,
             factor: 2.0,
             min_delay: Duration::from_secs(1),
             max_delay: Some(Duration::from_secs(60)),
             max_times: Some(3),
+            limit: DelayLimit::default(),
         }
     }
 }
@@ -28,11 +53,26 @@ impl ExponentialBuilder {
     /// If jitter is enabled, ExponentialBackoff will
     /// add a random jitter in `[0, min_delay)` to current delay.
     pub fn with_jitter(mut self) -> Self {
-        self.jitter = true;
+        self.jitter = Jitter::Additive;
+        self
+    }
+    pub fn with_jitter_mode(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+    /// Seed the random generator used by jitter, so the delays are reproducible.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
         self
     }
+    /// # Panics
+    ///
+    /// Panics unless `factor` is a finite number greater than 1.
     pub fn with_factor(mut self, factor: f32) -> Self {
-        debug_assert!(factor > 1.0, "invalid factor that lower than 1");
+        assert!(
+            factor.is_finite() && factor > 1.0,
+            "factor must be greater than 1, got {factor}"
+        );
         self.factor = factor;
         self
     }
@@ -44,10 +84,27 @@ impl ExponentialBuilder {
         self.max_delay = Some(max_delay);
         self
     }
+    /// Let the delay grow without bound, it saturates at `Duration::MAX`.
+    pub fn without_max_delay(mut self) -> Self {
+        self.max_delay = None;
+        self
+    }
     pub fn with_max_times(mut self, max_times: usize) -> Self {
         self.max_times = Some(max_times);
         self
     }
+    /// Stop the backoff once the sum of delays would exceed `total_delay`,
+    /// the last delay is clipped to fit.
+    pub fn with_total_delay(mut self, total_delay: Duration) -> Self {
+        self.limit.total_delay = Some(total_delay);
+        self
+    }
+    /// Stop the backoff once a delay would end after `deadline`,
+    /// the last delay is clipped to fit.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.limit.deadline = Some(deadline);
+        self
+    }
 }
 
 impl BackoffBuilder for ExponentialBuilder {
@@ -55,65 +112,126 @@ impl BackoffBuilder for ExponentialBuilder {
     fn build(&self) -> Self::Backoff {
         ExponentialBackoff {
             jitter: self.jitter,
+            seed: self.seed,
+            rng: None,
             factor: self.factor,
             min_delay: self.min_delay,
             max_delay: self.max_delay,
             max_times: self.max_times,
+            limit: self.limit.clone(),
 
             current_delay: None,
             attempts: 0,
         }
     }
 }
+
+impl ResumableBuilder for ExponentialBuilder {
+    fn resume(&self, state: &BackoffState) -> ExponentialBackoff {
+        let mut backoff = self.build();
+        backoff.attempts = state.attempts;
+        backoff.current_delay = state.current_delay;
+        backoff.limit.resume(state.slept);
+        backoff
+    }
+    fn state(backoff: &ExponentialBackoff) -> BackoffState {
+        BackoffState {
+            attempts: backoff.attempts,
+            current_delay: backoff.current_delay,
+            slept: backoff.limit.slept(),
+            last_failure: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ExponentialBackoff {
-    jitter: bool,
+    jitter: Jitter,
+    seed: Option<u64>,
+    // only seeded once jitter needs it
+    rng: Option<StdRng>,
     factor: f32,
     min_delay: Duration,
     max_delay: Option<Duration>,
     max_times: Option<usize>,
+    limit: DelayLimit,
 
     current_delay: Option<Duration>,
     attempts: usize,
 }
 
-impl Iterator for ExponentialBackoff {
-    type Item = Duration;
-    fn next(&mut self) -> Option<Self::Item> {
+/// Multiply a duration by a factor, saturating at `Duration::MAX` instead of panicking.
+fn saturating_mul_f64(dur: Duration, factor: f64) -> Duration {
+    Duration::try_from_secs_f64(dur.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+}
+
+impl ExponentialBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
         if self.attempts >= self.max_times.unwrap_or(usize::MAX) {
             return None;
         }
         self.attempts += 1;
-        match self.current_delay {
+        let cur = match self.current_delay {
             // first retry
-            None => {
-                let mut cur = self.min_delay;
-                self.current_delay = Some(cur);
-                if self.jitter {
-                    cur += self
-                        .min_delay
-                        .mul_f32(rand::thread_rng().gen_range(0.0..1.0));
-                }
-                Some(cur)
+            None => self.min_delay,
+            Some(cur) => saturating_mul_f64(cur, self.factor as f64),
+        };
+        let cur = self.clamp(cur);
+        self.current_delay = Some(cur);
+        let cur = match self.jitter {
+            Jitter::None => cur,
+            Jitter::Full => saturating_mul_f64(cur, self.rng().gen_range(0.0..=1.0)),
+            Jitter::Equal => {
+                let half = cur / 2;
+                half.saturating_add(saturating_mul_f64(
+                    cur - half,
+                    self.rng().gen_range(0.0..=1.0),
+                ))
             }
-            Some(mut cur) => {
-                if let Some(max_delay) = self.max_delay {
-                    if cur < max_delay {
-                        cur = cur.mul_f32(self.factor);
-                    }
-                }
-                self.current_delay = Some(cur);
-                if self.jitter {
-                    cur += self
-                        .min_delay
-                        .mul_f32(rand::thread_rng().gen_range(0.0..1.0));
-                }
-
-                Some(cur)
+            Jitter::Additive => {
+                let jitter = saturating_mul_f64(self.min_delay, self.rng().gen_range(0.0..1.0));
+                self.clamp(cur.saturating_add(jitter))
             }
+        };
+        Some(cur)
+    }
+
+    fn rng(&mut self) -> &mut StdRng {
+        let seed = self.seed;
+        self.rng.get_or_insert_with(|| match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(rand::thread_rng()).expect("thread_rng never fails"),
+        })
+    }
+
+    fn clamp(&self, delay: Duration) -> Duration {
+        match self.max_delay {
+            Some(max_delay) => delay.min(max_delay),
+            None => delay,
         }
     }
 }
+
+impl Iterator for ExponentialBackoff {
+    type Item = Duration;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(Instant::now())
+    }
+}
+
+impl Backoff for ExponentialBackoff {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let delay = self.next_delay();
+        self.limit.clip(now, delay)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        self.next_delay()?;
+        self.limit.clip(now, Some(delay))
+    }
+    fn deadline_reached(&self) -> bool {
+        self.limit.deadline_reached()
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -162,4 +280,126 @@ mod tests {
         assert_eq!(Some(Duration::from_millis(2000)), exp.next());
         assert_eq!(None, exp.next());
     }
+    #[test]
+    fn test_exponential_max_delay() {
+        let mut exp = ExponentialBuilder::default()
+            .with_max_times(5)
+            .with_factor(3.0)
+            .with_max_delay(Duration::from_secs(5))
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        assert_eq!(Some(Duration::from_secs(3)), exp.next());
+        assert_eq!(Some(Duration::from_secs(5)), exp.next());
+        assert_eq!(Some(Duration::from_secs(5)), exp.next());
+        assert_eq!(Some(Duration::from_secs(5)), exp.next());
+        assert_eq!(None, exp.next());
+    }
+    #[test]
+    fn test_exponential_without_max_delay() {
+        let mut exp = ExponentialBuilder::default()
+            .with_max_times(100)
+            .with_factor(1000.0)
+            .without_max_delay()
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        assert_eq!(Some(Duration::from_secs(1000)), exp.next());
+        assert_eq!(Some(Duration::from_secs(1_000_000)), exp.next());
+        assert_eq!(Some(Duration::MAX), exp.last());
+    }
+    #[test]
+    fn test_exponential_full_jitter() {
+        let mut exp = ExponentialBuilder::default()
+            .with_jitter_mode(Jitter::Full)
+            .build();
+
+        for max in [1, 2, 4] {
+            let v = exp.next().expect("value must valid");
+            assert!(v <= Duration::from_secs(max), "current: {v:?}");
+        }
+        assert_eq!(None, exp.next());
+    }
+    #[test]
+    fn test_exponential_equal_jitter() {
+        let mut exp = ExponentialBuilder::default()
+            .with_jitter_mode(Jitter::Equal)
+            .build();
+
+        for max in [1, 2, 4] {
+            let v = exp.next().expect("value must valid");
+            assert!(v >= Duration::from_secs(max) / 2, "current: {v:?}");
+            assert!(v <= Duration::from_secs(max), "current: {v:?}");
+        }
+        assert_eq!(None, exp.next());
+    }
+    #[test]
+    fn test_exponential_seed() {
+        let builder = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_jitter_mode(Jitter::Full)
+            .with_seed(42);
+
+        let first: Vec<_> = builder.build().collect();
+        let second: Vec<_> = builder.build().collect();
+        assert_eq!(10, first.len());
+        assert_eq!(first, second);
+    }
+    #[test]
+    fn test_exponential_seeds_only_for_jitter() {
+        let mut exp = ExponentialBuilder::default().build();
+        while exp.next().is_some() {}
+        assert!(exp.rng.is_none());
+
+        let mut exp = ExponentialBuilder::default().with_jitter().build();
+        exp.next();
+        assert!(exp.rng.is_some());
+    }
+    #[test]
+    #[should_panic(expected = "factor must be greater than 1, got 0.5")]
+    fn test_exponential_invalid_factor() {
+        let _ = ExponentialBuilder::default().with_factor(0.5);
+    }
+    #[test]
+    fn test_exponential_total_delay() {
+        let mut exp = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_total_delay(Duration::from_secs(5))
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        assert_eq!(Some(Duration::from_secs(2)), exp.next());
+        assert_eq!(Some(Duration::from_secs(2)), exp.next());
+        assert_eq!(None, exp.next());
+    }
+    #[test]
+    fn test_exponential_deadline() {
+        let mut exp = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_deadline(Instant::now() + Duration::from_millis(1500))
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        let v = exp.next().expect("value must valid");
+        assert!(v <= Duration::from_millis(1500), "current: {v:?}");
+        assert_eq!(None, exp.next());
+    }
+    #[test]
+    fn test_exponential_resume() {
+        let builder = ExponentialBuilder::default().with_max_times(5);
+        let mut exp = builder.build();
+        assert_eq!(Some(Duration::from_secs(1)), exp.next());
+        assert_eq!(Some(Duration::from_secs(2)), exp.next());
+
+        let state = ExponentialBuilder::state(&exp);
+        assert_eq!(2, state.attempts);
+        assert_eq!(Some(Duration::from_secs(2)), state.current_delay);
+        assert!(state.remaining_delay() <= Duration::from_secs(2));
+
+        let mut exp = builder.resume(&state);
+        assert_eq!(Some(Duration::from_secs(4)), exp.next());
+        assert_eq!(Some(Duration::from_secs(8)), exp.next());
+        assert_eq!(Some(Duration::from_secs(16)), exp.next());
+        assert_eq!(None, exp.next());
+    }
 }
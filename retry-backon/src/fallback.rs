@@ -0,0 +1,55 @@
+use std::future::{ready, Future, Ready};
+
+use crate::error::RetryError;
+
+/// Fallback produces a result once a `Retry` gives up,
+/// it's implemented for `FnOnce(RetryError<E>) -> Future<Output = Result<T, E>>`.
+pub trait Fallback<T, E> {
+    type Fut: Future<Output = Result<T, E>>;
+    fn call(self, err: RetryError<E>) -> Self::Fut;
+}
+
+/// impl Fallback for F: FnOnce(RetryError<E>) -> Future<Output = Result<T, E>>
+impl<T, E, F, Fut> Fallback<T, E> for F
+where
+    F: FnOnce(RetryError<E>) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    type Fut = Fut;
+    fn call(self, err: RetryError<E>) -> Self::Fut {
+        self(err)
+    }
+}
+
+/// BlockingFallback produces a result once a `BlockingRetry` gives up,
+/// it's implemented for `FnOnce(RetryError<E>) -> Result<T, E>`.
+pub trait BlockingFallback<T, E> {
+    fn call(self, err: RetryError<E>) -> Result<T, E>;
+}
+
+/// impl BlockingFallback for F: FnOnce(RetryError<E>) -> Result<T, E>
+impl<T, E, F> BlockingFallback<T, E> for F
+where
+    F: FnOnce(RetryError<E>) -> Result<T, E>,
+{
+    fn call(self, err: RetryError<E>) -> Result<T, E> {
+        self(err)
+    }
+}
+
+/// FallbackValue is the fallback used by `fallback_value`, it always returns its value.
+#[derive(Debug, Clone)]
+pub struct FallbackValue<T>(pub(crate) T);
+
+impl<T, E> Fallback<T, E> for FallbackValue<T> {
+    type Fut = Ready<Result<T, E>>;
+    fn call(self, _: RetryError<E>) -> Self::Fut {
+        ready(Ok(self.0))
+    }
+}
+
+impl<T, E> BlockingFallback<T, E> for FallbackValue<T> {
+    fn call(self, _: RetryError<E>) -> Result<T, E> {
+        Ok(self.0)
+    }
+}
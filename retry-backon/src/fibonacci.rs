@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use web_time::Instant;
+
+use crate::backoff::{Backoff, BackoffBuilder, BackoffState, DelayLimit, ResumableBuilder};
+#[derive(Debug, Clone)]
+pub struct FibonacciBuilder {
+    min_delay: Duration,
+    max_delay: Option<Duration>,
+    max_times: Option<usize>,
+    limit: DelayLimit,
+}
+
+impl Default for FibonacciBuilder {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_secs(1),
+            max_delay: Some(Duration::from_secs(60)),
+            max_times: Some(3),
+            limit: DelayLimit::default(),
+        }
+    }
+}
+
+impl FibonacciBuilder {
+    pub fn with_min_delay(mut self, min_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self
+    }
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+    pub fn with_max_times(mut self, max_times: usize) -> Self {
+        self.max_times = Some(max_times);
+        self
+    }
+    /// Stop the backoff once the sum of delays would exceed `total_delay`,
+    /// the last delay is clipped to fit.
+    pub fn with_total_delay(mut self, total_delay: Duration) -> Self {
+        self.limit.total_delay = Some(total_delay);
+        self
+    }
+    /// Stop the backoff once a delay would end after `deadline`,
+    /// the last delay is clipped to fit.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.limit.deadline = Some(deadline);
+        self
+    }
+}
+
+impl BackoffBuilder for FibonacciBuilder {
+    type Backoff = FibonacciBackoff;
+    fn build(&self) -> Self::Backoff {
+        FibonacciBackoff {
+            min_delay: self.min_delay,
+            max_delay: self.max_delay,
+            max_times: self.max_times,
+            limit: self.limit.clone(),
+
+            previous_delay: Duration::ZERO,
+            current_delay: None,
+            attempts: 0,
+        }
+    }
+}
+
+impl ResumableBuilder for FibonacciBuilder {
+    /// The delays taken are replayed, since the state keeps only the last one.
+    fn resume(&self, state: &BackoffState) -> FibonacciBackoff {
+        let mut backoff = self.build();
+        while backoff.attempts < state.attempts && backoff.next_delay().is_some() {}
+        backoff.limit.resume(state.slept);
+        backoff
+    }
+    fn state(backoff: &FibonacciBackoff) -> BackoffState {
+        BackoffState {
+            attempts: backoff.attempts,
+            current_delay: backoff.current_delay,
+            slept: backoff.limit.slept(),
+            last_failure: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FibonacciBackoff {
+    min_delay: Duration,
+    max_delay: Option<Duration>,
+    max_times: Option<usize>,
+    limit: DelayLimit,
+
+    previous_delay: Duration,
+    current_delay: Option<Duration>,
+    attempts: usize,
+}
+
+impl FibonacciBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempts >= self.max_times.unwrap_or(usize::MAX) {
+            return None;
+        }
+        self.attempts += 1;
+        let cur = match self.current_delay {
+            // first retry
+            None => self.min_delay,
+            Some(cur) => self.previous_delay.saturating_add(cur),
+        };
+        let cur = match self.max_delay {
+            Some(max_delay) => cur.min(max_delay),
+            None => cur,
+        };
+        self.previous_delay = self.current_delay.unwrap_or(Duration::ZERO);
+        self.current_delay = Some(cur);
+        Some(cur)
+    }
+}
+
+impl Iterator for FibonacciBackoff {
+    type Item = Duration;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(Instant::now())
+    }
+}
+
+impl Backoff for FibonacciBackoff {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let delay = self.next_delay();
+        self.limit.clip(now, delay)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        self.next_delay()?;
+        self.limit.clip(now, Some(delay))
+    }
+    fn deadline_reached(&self) -> bool {
+        self.limit.deadline_reached()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_fibonacci_default() {
+        let mut fib = FibonacciBuilder::default().build();
+
+        assert_eq!(Some(Duration::from_secs(1)), fib.next());
+        assert_eq!(Some(Duration::from_secs(1)), fib.next());
+        assert_eq!(Some(Duration::from_secs(2)), fib.next());
+        assert_eq!(None, fib.next());
+    }
+    #[test]
+    fn test_fibonacci_max_times() {
+        let mut fib = FibonacciBuilder::default().with_max_times(6).build();
+
+        assert_eq!(Some(Duration::from_secs(1)), fib.next());
+        assert_eq!(Some(Duration::from_secs(1)), fib.next());
+        assert_eq!(Some(Duration::from_secs(2)), fib.next());
+        assert_eq!(Some(Duration::from_secs(3)), fib.next());
+        assert_eq!(Some(Duration::from_secs(5)), fib.next());
+        assert_eq!(Some(Duration::from_secs(8)), fib.next());
+        assert_eq!(None, fib.next());
+    }
+    #[test]
+    fn test_fibonacci_max_delay() {
+        let mut fib = FibonacciBuilder::default()
+            .with_max_times(5)
+            .with_max_delay(Duration::from_secs(3))
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), fib.next());
+        assert_eq!(Some(Duration::from_secs(1)), fib.next());
+        assert_eq!(Some(Duration::from_secs(2)), fib.next());
+        assert_eq!(Some(Duration::from_secs(3)), fib.next());
+        assert_eq!(Some(Duration::from_secs(3)), fib.next());
+        assert_eq!(None, fib.next());
+    }
+    #[test]
+    fn test_fibonacci_min_delay() {
+        let mut fib = FibonacciBuilder::default()
+            .with_min_delay(Duration::from_millis(500))
+            .build();
+
+        assert_eq!(Some(Duration::from_millis(500)), fib.next());
+        assert_eq!(Some(Duration::from_millis(500)), fib.next());
+        assert_eq!(Some(Duration::from_millis(1000)), fib.next());
+        assert_eq!(None, fib.next());
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use pin_project::pin_project;
+
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    sleep::{DefaultSleeper, Sleeper},
+};
+
+pub trait Hedgeable<
+    B: BackoffBuilder,
+    T,
+    E,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+>
+{
+    fn hedge(self, builder: &B) -> Hedge<B::Backoff, T, E, Fut, FutureFn>;
+}
+
+/// impl Hedgeable for FutureFn: FnMut()->Future<Output = Result<T, E>>
+impl<B, T, E, Fut, FutureFn> Hedgeable<B, T, E, Fut, FutureFn> for FutureFn
+where
+    B: BackoffBuilder,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+{
+    fn hedge(self, builder: &B) -> Hedge<B::Backoff, T, E, Fut, FutureFn> {
+        Hedge::new(self, builder.build())
+    }
+}
+
+/// HedgeStats counts how hedged calls end, clones share the same counters
+/// so one instance can be given to many calls to tune the hedge delays.
+#[derive(Debug, Clone, Default)]
+pub struct HedgeStats {
+    inner: Arc<StatsInner>,
+}
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    calls: AtomicUsize,
+    hedges: AtomicUsize,
+    primary_wins: AtomicUsize,
+    hedge_wins: AtomicUsize,
+}
+
+impl HedgeStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Number of hedged calls started.
+    pub fn calls(&self) -> usize {
+        self.inner.calls.load(Ordering::Relaxed)
+    }
+    /// Number of backup attempts fired.
+    pub fn hedges(&self) -> usize {
+        self.inner.hedges.load(Ordering::Relaxed)
+    }
+    /// Number of calls won by the first attempt.
+    pub fn primary_wins(&self) -> usize {
+        self.inner.primary_wins.load(Ordering::Relaxed)
+    }
+    /// Number of calls won by a backup attempt.
+    pub fn hedge_wins(&self) -> usize {
+        self.inner.hedge_wins.load(Ordering::Relaxed)
+    }
+}
+
+/// Hedge starts an attempt, then fires a backup attempt after each delay
+/// of the backoff while no attempt succeeded. The first `Ok` is returned
+/// and the other attempts are dropped.
+#[pin_project]
+pub struct Hedge<
+    B: Backoff,
+    T,
+    E,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper = DefaultSleeper,
+> {
+    backoff: B,
+    retryable: fn(&E) -> bool,
+    max_in_flight: usize,
+    stats: HedgeStats,
+    future_fn: FutureFn,
+    sleeper: SF,
+    started: bool,
+    due: bool,
+    attempts: usize,
+    in_flight: Vec<(usize, Pin<Box<Fut>>)>,
+    last_err: Option<E>,
+    #[pin]
+    timer: Option<SF::Sleep>,
+}
+
+impl<B, T, E, Fut, FutureFn> Hedge<B, T, E, Fut, FutureFn>
+where
+    B: Backoff,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+{
+    fn new(future_fn: FutureFn, backoff: B) -> Self {
+        Self {
+            backoff,
+            retryable: |_: &E| true,
+            max_in_flight: 2,
+            stats: HedgeStats::default(),
+            future_fn,
+            sleeper: DefaultSleeper::default(),
+            started: false,
+            due: false,
+            attempts: 0,
+            in_flight: Vec::new(),
+            last_err: None,
+            timer: None,
+        }
+    }
+}
+
+impl<B, T, E, Fut, FutureFn, SF> Hedge<B, T, E, Fut, FutureFn, SF>
+where
+    B: Backoff,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper,
+{
+    /// Replace the sleeper used to wait for the hedge delays.
+    pub fn sleep<SN: Sleeper>(self, sleeper: SN) -> Hedge<B, T, E, Fut, FutureFn, SN> {
+        Hedge {
+            backoff: self.backoff,
+            retryable: self.retryable,
+            max_in_flight: self.max_in_flight,
+            stats: self.stats,
+            future_fn: self.future_fn,
+            sleeper,
+            started: false,
+            due: false,
+            attempts: 0,
+            in_flight: Vec::new(),
+            last_err: None,
+            timer: None,
+        }
+    }
+    /// Errors rejected by `retryable` end the call at once.
+    pub fn when(mut self, retryable: fn(&E) -> bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+    /// Limit the attempts running at the same time, defaults to 2.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        debug_assert!(max_in_flight > 0, "invalid max_in_flight that lower than 1");
+        self.max_in_flight = max_in_flight;
+        self
+    }
+    /// Record the outcome of this call into `stats`.
+    pub fn with_stats(mut self, stats: HedgeStats) -> Self {
+        self.stats = stats;
+        self
+    }
+}
+
+/// impl Future for Hedge
+impl<B, T, E, Fut, FutureFn, SF> Future for Hedge<B, T, E, Fut, FutureFn, SF>
+where
+    B: Backoff,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper,
+{
+    type Output = Result<T, E>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        if !*this.started {
+            *this.started = true;
+            *this.due = true;
+            this.stats.inner.calls.fetch_add(1, Ordering::Relaxed);
+        }
+        loop {
+            if let Some(timer) = this.timer.as_mut().as_pin_mut() {
+                if timer.poll(cx).is_ready() {
+                    this.timer.set(None);
+                    *this.due = true;
+                }
+            }
+            if *this.due && this.in_flight.len() < *this.max_in_flight {
+                *this.due = false;
+                if *this.attempts > 0 {
+                    this.stats.inner.hedges.fetch_add(1, Ordering::Relaxed);
+                }
+                this.in_flight
+                    .push((*this.attempts, Box::pin((this.future_fn)())));
+                *this.attempts += 1;
+                let timer = this
+                    .backoff
+                    .next_at(this.sleeper.now())
+                    .map(|dur| this.sleeper.sleep(dur));
+                this.timer.set(timer);
+                continue;
+            }
+
+            let mut i = 0;
+            while i < this.in_flight.len() {
+                let (attempt, fut) = &mut this.in_flight[i];
+                match fut.as_mut().poll(cx) {
+                    Poll::Pending => i += 1,
+                    Poll::Ready(Ok(v)) => {
+                        let wins = match attempt {
+                            0 => &this.stats.inner.primary_wins,
+                            _ => &this.stats.inner.hedge_wins,
+                        };
+                        wins.fetch_add(1, Ordering::Relaxed);
+                        this.in_flight.clear();
+                        this.timer.set(None);
+                        return Poll::Ready(Ok(v));
+                    }
+                    Poll::Ready(Err(err)) => {
+                        drop(this.in_flight.swap_remove(i));
+                        if !(this.retryable)(&err) {
+                            this.in_flight.clear();
+                            this.timer.set(None);
+                            return Poll::Ready(Err(err));
+                        }
+                        *this.last_err = Some(err);
+                    }
+                }
+            }
+
+            // a failed attempt freed a slot for a due hedge
+            if *this.due && this.in_flight.len() < *this.max_in_flight {
+                continue;
+            }
+            if this.in_flight.is_empty() && !*this.due && this.timer.is_none() {
+                let err = this.last_err.take().expect("an attempt must have failed");
+                return Poll::Ready(Err(err));
+            }
+            return Poll::Pending;
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tokio::sync::Mutex;
+
+    use crate::constant::ConstantBuilder;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hedge_wins() -> Result<()> {
+        let attempts = Mutex::new(0);
+        let f = || async {
+            let attempt = {
+                let mut x = attempts.lock().await;
+                *x += 1;
+                *x
+            };
+            if attempt == 1 {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+                return Ok::<_, anyhow::Error>("primary");
+            }
+            Ok("hedge")
+        };
+        let stats = HedgeStats::new();
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(10));
+        let result = f.hedge(&backoff).with_stats(stats.clone()).await;
+        assert_eq!("hedge", result?);
+        assert_eq!(1, stats.calls());
+        assert_eq!(1, stats.hedges());
+        assert_eq!(1, stats.hedge_wins());
+        assert_eq!(0, stats.primary_wins());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_hedge_primary_wins() -> Result<()> {
+        let f = || async { Ok::<_, anyhow::Error>("primary") };
+        let stats = HedgeStats::new();
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(10));
+        let result = f.hedge(&backoff).with_stats(stats.clone()).await;
+        assert_eq!("primary", result?);
+        assert_eq!(0, stats.hedges());
+        assert_eq!(1, stats.primary_wins());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_hedge_all_failed() -> Result<()> {
+        let attempts = Mutex::new(0);
+        let f = || async {
+            let mut x = attempts.lock().await;
+            *x += 1;
+            Err::<(), _>(anyhow::anyhow!("error {x}"))
+        };
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let result = f.hedge(&backoff).await;
+        assert_eq!("error 4", result.unwrap_err().to_string());
+        assert_eq!(4, *attempts.lock().await);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_hedge_max_in_flight() -> Result<()> {
+        let attempts = Mutex::new(0);
+        let f = || async {
+            *attempts.lock().await += 1;
+            std::future::pending::<Result<()>>().await
+        };
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let result = tokio::time::timeout(
+            Duration::from_millis(50),
+            f.hedge(&backoff).with_max_in_flight(3),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(3, *attempts.lock().await);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+use std::{error::Error, fmt, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use reqwest::{header::RETRY_AFTER, Client, Method, Request, Response, StatusCode};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    backoff::BackoffBuilder,
+    error::{AttemptTimeout, Cancelled, CircuitOpen, RetryDecision},
+    retry::{Retry, Retryable},
+    sleep::{system_time, DefaultSleeper, Sleeper},
+};
+
+/// HttpPolicy retries requests sent with reqwest.
+///
+/// Only idempotent methods are retried by default. Responses with status
+/// 408, 429 or 5xx and connection or timeout errors are transient, and a
+/// `Retry-After` header overrides the next delay of the backoff.
+#[derive(Debug, Clone)]
+pub struct HttpPolicy<SF = DefaultSleeper> {
+    methods: Vec<Method>,
+    max_retry_after: Option<Duration>,
+    sleeper: SF,
+}
+
+impl Default for HttpPolicy {
+    fn default() -> Self {
+        Self {
+            methods: vec![
+                Method::GET,
+                Method::HEAD,
+                Method::OPTIONS,
+                Method::TRACE,
+                Method::PUT,
+                Method::DELETE,
+            ],
+            max_retry_after: None,
+            sleeper: DefaultSleeper::default(),
+        }
+    }
+}
+
+impl HttpPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<SF: Sleeper> HttpPolicy<SF> {
+    /// Replace the methods allowed to be retried.
+    pub fn with_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+    /// Give up instead of waiting when `Retry-After` asks for more than `max`.
+    pub fn with_max_retry_after(mut self, max: Duration) -> Self {
+        self.max_retry_after = Some(max);
+        self
+    }
+    pub fn sleep<SN: Sleeper>(self, sleeper: SN) -> HttpPolicy<SN> {
+        HttpPolicy {
+            methods: self.methods,
+            max_retry_after: self.max_retry_after,
+            sleeper,
+        }
+    }
+
+    /// Check whether `result` of a request with `method` should be retried.
+    pub fn is_retryable(&self, method: &Method, result: &reqwest::Result<Response>) -> bool {
+        if !self.methods.contains(method) {
+            return false;
+        }
+        match result {
+            Ok(resp) => is_transient_status(resp.status()),
+            Err(err) => err.is_connect() || err.is_timeout(),
+        }
+    }
+}
+
+impl<SF> HttpPolicy<SF>
+where
+    SF: Sleeper + Clone + Send + Sync + 'static,
+{
+    /// Build the retry of `request` sent with `client`, after the delays of `builder`.
+    ///
+    /// Transient responses and errors fail the attempt with an `HttpFailure`, other
+    /// responses succeed. The policy's classification is applied by `when` and its
+    /// `Retry-After` delay by `decide`, so replacing either drops it. Everything else
+    /// of `Retry` can be added, e.g. `notify`, `with_cancellation` or `with_report`.
+    /// Requests whose body can't be cloned are sent only once.
+    pub fn retry<B: BackoffBuilder>(
+        &self,
+        client: &Client,
+        request: Request,
+        builder: &B,
+    ) -> HttpRetry<B::Backoff, SF> {
+        let policy = Arc::new(self.clone());
+        let client = client.clone();
+        let mut next = Some(request);
+        let attempt: HttpAttemptFn = Box::new(move || {
+            let request = next
+                .take()
+                .expect("a request whose body can't be cloned is sent only once");
+            next = request.try_clone();
+            let (policy, client, cloned) = (policy.clone(), client.clone(), next.is_some());
+            Box::pin(async move {
+                let method = request.method().clone();
+                let result = client.execute(request).await;
+                let now = system_time(policy.sleeper.now());
+                let after = result.as_ref().ok().and_then(|resp| retry_after(resp, now));
+                let too_long = matches!(
+                    (after, policy.max_retry_after),
+                    (Some(after), Some(max)) if after > max
+                );
+                let retryable = cloned && !too_long && policy.is_retryable(&method, &result);
+                let failure = |kind| HttpFailure {
+                    kind,
+                    retryable,
+                    retry_after: after,
+                };
+                match result {
+                    Ok(resp) if !is_transient_status(resp.status()) => Ok(resp),
+                    Ok(resp) => Err(failure(HttpFailureKind::Status(resp))),
+                    Err(err) => Err(failure(HttpFailureKind::Error(err))),
+                }
+            })
+        });
+        attempt
+            .retry(builder)
+            .sleep(self.sleeper.clone())
+            .when(HttpFailure::is_retryable)
+            .decide(|failure| {
+                failure
+                    .retry_after
+                    .map_or(RetryDecision::Retry, RetryDecision::RetryAfter)
+            })
+    }
+
+    /// Send `request` with `client`, retrying transient failures after the delays of `builder`.
+    ///
+    /// The last response or error is returned once the backoff is exhausted.
+    /// Requests whose body can't be cloned are sent only once.
+    pub async fn send<B: BackoffBuilder>(
+        &self,
+        client: &Client,
+        request: Request,
+        builder: &B,
+    ) -> reqwest::Result<Response> {
+        match self.retry(client, request, builder).await {
+            Ok(resp) => Ok(resp),
+            Err(failure) => match failure.into_kind() {
+                HttpFailureKind::Status(resp) => Ok(resp),
+                HttpFailureKind::Error(err) => Err(err),
+                kind => unreachable!("send neither cancels nor times out: {kind:?}"),
+            },
+        }
+    }
+}
+
+/// The attempts of `HttpPolicy::retry`, boxed so the retry can be named.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+pub type HttpAttempt = Pin<Box<dyn Future<Output = Result<Response, HttpFailure>> + Send>>;
+/// The attempts of `HttpPolicy::retry`, boxed so the retry can be named.
+/// reqwest's futures are not `Send` in the browser.
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+pub type HttpAttempt = Pin<Box<dyn Future<Output = Result<Response, HttpFailure>>>>;
+
+/// The function making the attempts of `HttpPolicy::retry`.
+pub type HttpAttemptFn = Box<dyn FnMut() -> HttpAttempt + Send>;
+
+/// The retry built by `HttpPolicy::retry`.
+pub type HttpRetry<B, SF = DefaultSleeper> =
+    Retry<B, Response, HttpFailure, HttpAttempt, HttpAttemptFn, SF>;
+
+/// HttpFailure is an attempt of `HttpPolicy::retry` that failed,
+/// with the classification of the policy.
+#[derive(Debug)]
+pub struct HttpFailure {
+    kind: HttpFailureKind,
+    retryable: bool,
+    retry_after: Option<Duration>,
+}
+
+/// HttpFailureKind is what an attempt of `HttpPolicy::retry` failed with.
+#[derive(Debug)]
+pub enum HttpFailureKind {
+    /// A response with a transient status, 408, 429 or 5xx.
+    Status(Response),
+    /// An error of reqwest, e.g. a connection error.
+    Error(reqwest::Error),
+    Cancelled(Cancelled),
+    AttemptTimeout(AttemptTimeout),
+    CircuitOpen(CircuitOpen),
+}
+
+impl HttpFailure {
+    pub fn kind(&self) -> &HttpFailureKind {
+        &self.kind
+    }
+    pub fn into_kind(self) -> HttpFailureKind {
+        self.kind
+    }
+    /// Whether the policy retries the failure.
+    pub fn is_retryable(&self) -> bool {
+        self.retryable
+    }
+    /// The delay asked by the `Retry-After` header, or the rest of an open circuit's cool-down.
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+}
+
+impl From<Cancelled> for HttpFailure {
+    fn from(cancelled: Cancelled) -> Self {
+        Self {
+            kind: HttpFailureKind::Cancelled(cancelled),
+            retryable: false,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<AttemptTimeout> for HttpFailure {
+    fn from(timeout: AttemptTimeout) -> Self {
+        Self {
+            kind: HttpFailureKind::AttemptTimeout(timeout),
+            retryable: true,
+            retry_after: None,
+        }
+    }
+}
+
+impl From<CircuitOpen> for HttpFailure {
+    fn from(open: CircuitOpen) -> Self {
+        Self {
+            kind: HttpFailureKind::CircuitOpen(open),
+            retryable: true,
+            retry_after: Some(open.0),
+        }
+    }
+}
+
+impl fmt::Display for HttpFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            HttpFailureKind::Status(resp) => write!(f, "transient status {}", resp.status()),
+            HttpFailureKind::Error(err) => err.fmt(f),
+            HttpFailureKind::Cancelled(cancelled) => cancelled.fmt(f),
+            HttpFailureKind::AttemptTimeout(timeout) => timeout.fmt(f),
+            HttpFailureKind::CircuitOpen(open) => open.fmt(f),
+        }
+    }
+}
+
+impl Error for HttpFailure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match &self.kind {
+            HttpFailureKind::Error(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Check whether `status` is 408, 429 or 5xx.
+pub fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+/// Read the delay asked by the `Retry-After` header of `resp`,
+/// an HTTP-date is turned into a delay from `now`.
+pub fn retry_after(resp: &Response, now: SystemTime) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, now)
+}
+
+/// Parse a `Retry-After` value, either delay seconds or an HTTP-date
+/// which is turned into a delay from `now`. A date in the past gives a zero delay.
+pub fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = from_std(httpdate::parse_http_date(value).ok()?)?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+// httpdate works with std's SystemTime, which can't be read on wasm32
+fn from_std(time: std::time::SystemTime) -> Option<SystemTime> {
+    let since_epoch = time.duration_since(std::time::UNIX_EPOCH).ok()?;
+    UNIX_EPOCH.checked_add(since_epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use anyhow::Result;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        cancel::CancellationToken, constant::ConstantBuilder, error::StopReason, sleep::TestClock,
+    };
+
+    use super::*;
+
+    /// Serve `responses` in order on a local port, repeating the last one,
+    /// and count the requests received.
+    async fn mock_server(responses: Vec<&'static str>) -> Result<(String, Arc<AtomicUsize>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = format!("http://{}/", listener.local_addr()?);
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let resp = responses[n.min(responses.len() - 1)];
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(resp.as_bytes()).await;
+            }
+        });
+        Ok((addr, hits))
+    }
+
+    const UNAVAILABLE: &str =
+        "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+    const OK: &str = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+
+    fn backoff() -> ConstantBuilder {
+        ConstantBuilder::default().with_delay(Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn test_http_retry_transient_status() -> Result<()> {
+        let (addr, hits) = mock_server(vec![UNAVAILABLE, UNAVAILABLE, OK]).await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        let resp = HttpPolicy::new().send(&client, req, &backoff()).await?;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("ok", resp.text().await?);
+        assert_eq!(3, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_not_idempotent() -> Result<()> {
+        let (addr, hits) = mock_server(vec![UNAVAILABLE, OK]).await?;
+        let client = Client::new();
+        let req = client.post(&addr).body("hello").build()?;
+        let resp = HttpPolicy::new().send(&client, req, &backoff()).await?;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+
+        let req = client.post(&addr).body("hello").build()?;
+        let resp = HttpPolicy::new()
+            .with_methods([Method::POST])
+            .send(&client, req, &backoff())
+            .await?;
+        assert_eq!(StatusCode::OK, resp.status());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_permanent_status() -> Result<()> {
+        let (addr, hits) = mock_server(vec![
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        ])
+        .await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        let resp = HttpPolicy::new().send(&client, req, &backoff()).await?;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_exhausted() -> Result<()> {
+        let (addr, hits) = mock_server(vec![UNAVAILABLE]).await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        let resp = HttpPolicy::new().send(&client, req, &backoff()).await?;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!(4, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_connection_error() -> Result<()> {
+        // bind then drop to get a port nobody listens on
+        let addr = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let client = Client::new();
+        let req = client.get(format!("http://{addr}/")).build()?;
+        let policy = HttpPolicy::new();
+        let result = client.execute(req.try_clone().unwrap()).await;
+        assert!(policy.is_retryable(&Method::GET, &result));
+        assert!(policy
+            .send(&client, req, &backoff())
+            .await
+            .unwrap_err()
+            .is_connect());
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_retry_after() -> Result<()> {
+        let (addr, hits) = mock_server(vec![
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            OK,
+        ])
+        .await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        // the constant delay would wait a minute, the header asks for none
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_secs(60));
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            HttpPolicy::new().send(&client, req, &backoff),
+        )
+        .await??;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_max_retry_after() -> Result<()> {
+        let (addr, hits) = mock_server(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nretry-after: 3600\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            OK,
+        ])
+        .await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        let resp = HttpPolicy::new()
+            .with_max_retry_after(Duration::from_secs(10))
+            .send(&client, req, &backoff())
+            .await?;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_retry_notify_and_report() -> Result<()> {
+        static NOTIFIED: AtomicUsize = AtomicUsize::new(0);
+        let (addr, hits) = mock_server(vec![UNAVAILABLE]).await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        let err = HttpPolicy::new()
+            .retry(&client, req, &backoff())
+            .notify(|_, _| {
+                NOTIFIED.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_report()
+            .await
+            .unwrap_err();
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!(4, err.attempts());
+        assert_eq!(3, NOTIFIED.load(Ordering::SeqCst));
+        assert_eq!(4, hits.load(Ordering::SeqCst));
+        assert!(matches!(
+            err.last_error().kind(),
+            HttpFailureKind::Status(resp) if resp.status() == StatusCode::SERVICE_UNAVAILABLE
+        ));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_retry_cancelled() -> Result<()> {
+        let (addr, hits) = mock_server(vec![OK]).await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = HttpPolicy::new()
+            .retry(&client, req, &backoff())
+            .with_cancellation(token)
+            .await
+            .unwrap_err();
+        assert!(matches!(err.kind(), HttpFailureKind::Cancelled(_)));
+        assert_eq!(0, hits.load(Ordering::SeqCst));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_http_retry_after_date_on_sleeper_clock() -> Result<()> {
+        let date = httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(120));
+        let limited = format!(
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: {date}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+        );
+        let (addr, _) = mock_server(vec![limited.leak(), OK]).await?;
+        let client = Client::new();
+        let req = client.get(&addr).build()?;
+        // the virtual clock is already a minute ahead, so only the other minute is left
+        let clock = TestClock::new();
+        clock.advance(Duration::from_secs(60));
+        let resp = HttpPolicy::new()
+            .sleep(clock.clone())
+            .send(&client, req, &backoff())
+            .await?;
+        assert_eq!(StatusCode::OK, resp.status());
+        let sleeps = clock.sleeps();
+        assert_eq!(1, sleeps.len());
+        assert!(sleeps[0] > Duration::from_secs(55) && sleeps[0] <= Duration::from_secs(60));
+        Ok(())
+    }
+    #[test]
+    fn test_parse_retry_after() {
+        let now =
+            from_std(httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap()).unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after("120", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now)
+        );
+        assert_eq!(
+            Some(Duration::ZERO),
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after("soon", now));
+    }
+}
@@ -0,0 +1,322 @@
+use std::{future::Future, time::Duration};
+
+use crate::error::StopReason;
+
+/// An attempt of an async retry, polled inside the span of its `Instrument`.
+#[cfg(feature = "tracing")]
+pub(crate) type Attempt<Fut> = tracing::instrument::Instrumented<Fut>;
+#[cfg(not(feature = "tracing"))]
+pub(crate) type Attempt<Fut> = Fut;
+
+/// Instrument reports the progress of a retried operation
+/// to `tracing` and `metrics` when those features are enabled.
+///
+/// A span named `retry` is opened on the first attempt, attempts, retries
+/// and the outcome are recorded as events inside it. The attempts run inside
+/// the span too, so the events of the operation nest under it. The counters
+/// `retry_backon_attempts_total`, `retry_backon_retries_total`,
+/// `retry_backon_give_ups_total` and `retry_backon_not_retryable_total`
+/// and the histogram `retry_backon_backoff_seconds` of the total delay slept
+/// by a call are labelled by `operation`.
+#[derive(Debug)]
+pub(crate) struct Instrument {
+    pub(crate) name: &'static str,
+    attempts: usize,
+    slept: Duration,
+    #[cfg(feature = "tracing")]
+    span: Option<tracing::Span>,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self {
+            name: "unnamed",
+            attempts: 0,
+            slept: Duration::ZERO,
+            #[cfg(feature = "tracing")]
+            span: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "tracing", feature = "metrics"))]
+fn reason_label(reason: StopReason) -> &'static str {
+    match reason {
+        StopReason::NotRetryable => "not_retryable",
+        StopReason::Exhausted => "exhausted",
+        StopReason::Deadline => "deadline",
+        StopReason::Cancelled => "cancelled",
+    }
+}
+
+impl Instrument {
+    pub(crate) fn attempt(&mut self) {
+        self.attempts += 1;
+        #[cfg(feature = "tracing")]
+        {
+            let name = self.name;
+            let span = self
+                .span
+                .get_or_insert_with(|| tracing::info_span!("retry", operation = name));
+            tracing::debug!(parent: &*span, attempt = self.attempts, "attempt started");
+        }
+        #[cfg(feature = "metrics")]
+        metrics::counter!("retry_backon_attempts_total", "operation" => self.name).increment(1);
+    }
+
+    /// Run `f`, which makes the current attempt, inside the span.
+    pub(crate) fn in_span<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        if let Some(span) = &self.span {
+            return span.in_scope(f);
+        }
+        f()
+    }
+
+    /// Wrap the future of the current attempt so it's polled inside the span.
+    pub(crate) fn instrument<Fut: Future>(&self, fut: Fut) -> Attempt<Fut> {
+        #[cfg(feature = "tracing")]
+        {
+            use tracing::Instrument as _;
+            let span = self.span.clone().unwrap_or_else(tracing::Span::none);
+            fut.instrument(span)
+        }
+        #[cfg(not(feature = "tracing"))]
+        fut
+    }
+
+    pub(crate) fn retry(&mut self, delay: Duration) {
+        self.slept = self.slept.saturating_add(delay);
+        #[cfg(feature = "tracing")]
+        if let Some(span) = &self.span {
+            tracing::info!(
+                parent: span,
+                attempt = self.attempts,
+                delay_ms = delay.as_millis() as u64,
+                "attempt failed, retrying"
+            );
+        }
+        #[cfg(feature = "metrics")]
+        metrics::counter!("retry_backon_retries_total", "operation" => self.name).increment(1);
+    }
+
+    pub(crate) fn succeed(&mut self) {
+        #[cfg(feature = "tracing")]
+        if let Some(span) = &self.span {
+            tracing::debug!(parent: span, attempts = self.attempts, "succeeded");
+        }
+        self.record_backoff();
+    }
+
+    pub(crate) fn give_up(&mut self, reason: StopReason) {
+        #[cfg(feature = "tracing")]
+        {
+            let name = self.name;
+            let span = self
+                .span
+                .get_or_insert_with(|| tracing::info_span!("retry", operation = name));
+            tracing::warn!(
+                parent: &*span,
+                attempts = self.attempts,
+                reason = reason_label(reason),
+                "gave up"
+            );
+        }
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!(
+                "retry_backon_give_ups_total",
+                "operation" => self.name,
+                "reason" => reason_label(reason)
+            )
+            .increment(1);
+            if reason == StopReason::NotRetryable {
+                metrics::counter!("retry_backon_not_retryable_total", "operation" => self.name)
+                    .increment(1);
+            }
+        }
+        #[cfg(not(any(feature = "tracing", feature = "metrics")))]
+        let _ = reason;
+        self.record_backoff();
+    }
+
+    fn record_backoff(&self) {
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("retry_backon_backoff_seconds", "operation" => self.name)
+            .record(self.slept.as_secs_f64());
+    }
+}
+
+#[cfg(all(test, any(feature = "tracing", feature = "metrics")))]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use crate::{blocking_retry::BlockingRetryable, constant::ConstantBuilder};
+
+    fn backoff() -> ConstantBuilder {
+        ConstantBuilder::default().with_delay(Duration::from_millis(1))
+    }
+
+    /// Fail `failures` times, then succeed.
+    fn flaky(failures: usize) -> impl FnMut() -> Result<()> {
+        let mut calls = 0;
+        move || {
+            calls += 1;
+            if calls <= failures {
+                return Err(anyhow::anyhow!("retryable"));
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let _ = flaky(2).retry(&backoff()).with_name("fetch").call();
+            let _ = flaky(10)
+                .retry(&backoff())
+                .with_name("fetch")
+                .when(|_| false)
+                .call();
+        });
+
+        let mut counters = Vec::new();
+        let mut backoff = Vec::new();
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            let key = key.key();
+            assert!(key
+                .labels()
+                .any(|l| l.key() == "operation" && l.value() == "fetch"));
+            match value {
+                DebugValue::Counter(n) => {
+                    let reason = key.labels().find(|l| l.key() == "reason");
+                    counters.push((
+                        key.name().to_string(),
+                        reason.map(|l| l.value().to_string()),
+                        n,
+                    ))
+                }
+                DebugValue::Histogram(values) => {
+                    backoff.extend(values.into_iter().map(|v| v.into_inner()))
+                }
+                DebugValue::Gauge(_) => unreachable!(),
+            }
+        }
+        counters.sort();
+        assert_eq!(
+            vec![
+                ("retry_backon_attempts_total".to_string(), None, 4),
+                (
+                    "retry_backon_give_ups_total".to_string(),
+                    Some("not_retryable".to_string()),
+                    1
+                ),
+                ("retry_backon_not_retryable_total".to_string(), None, 1),
+                ("retry_backon_retries_total".to_string(), None, 2),
+            ],
+            counters
+        );
+        backoff.sort_by(f64::total_cmp);
+        assert_eq!(2, backoff.len());
+        assert_eq!(0.0, backoff[0]);
+        assert!(backoff[1] >= 0.002, "backoff: {backoff:?}");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing() {
+        use std::sync::{Arc, Mutex};
+
+        use crate::retry::Retryable;
+        use tracing::{field::Field, span, Event, Subscriber};
+        use tracing_subscriber::{
+            layer::{Context, SubscriberExt},
+            registry::LookupSpan,
+            Layer,
+        };
+
+        /// Record the span and message of every event.
+        #[derive(Clone, Default)]
+        struct Events(Arc<Mutex<Vec<(String, String)>>>);
+
+        impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Events {
+            fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+                struct Message(String);
+                impl tracing::field::Visit for Message {
+                    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                        if field.name() == "message" {
+                            self.0 = format!("{value:?}");
+                        }
+                    }
+                }
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                let span = ctx
+                    .event_span(event)
+                    .map(|s| s.name().to_string())
+                    .unwrap_or_default();
+                self.0.lock().unwrap().push((span, message.0));
+            }
+            fn on_new_span(&self, _: &span::Attributes<'_>, _: &span::Id, _: Context<'_, S>) {}
+        }
+
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            let _ = flaky(1).retry(&backoff()).with_name("fetch").call();
+            let _ = flaky(10).retry(&backoff()).call();
+            // the events of the operation itself nest under the span too
+            let _ = (|| -> Result<()> {
+                tracing::info!("blocking operation");
+                Ok(())
+            })
+            .retry(&backoff())
+            .call();
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .unwrap();
+            let _ = rt.block_on(
+                (|| async {
+                    tracing::info!("async operation");
+                    Ok::<_, anyhow::Error>(())
+                })
+                .retry(&backoff()),
+            );
+        });
+
+        let events = events.0.lock().unwrap();
+        let messages: Vec<_> = events.iter().map(|(_, m)| m.as_str()).collect();
+        assert!(events.iter().all(|(span, _)| span == "retry"));
+        assert_eq!(
+            vec![
+                "attempt started",
+                "attempt failed, retrying",
+                "attempt started",
+                "succeeded",
+                "attempt started",
+                "attempt failed, retrying",
+                "attempt started",
+                "attempt failed, retrying",
+                "attempt started",
+                "attempt failed, retrying",
+                "attempt started",
+                "gave up",
+                "attempt started",
+                "blocking operation",
+                "succeeded",
+                "attempt started",
+                "async operation",
+                "succeeded",
+            ],
+            messages
+        );
+    }
+}
@@ -1,5 +1,28 @@
 pub mod backoff;
 pub mod blocking_retry;
+pub mod cancel;
+pub mod circuit_breaker;
+#[cfg(feature = "serde")]
+pub mod config;
 pub mod constant;
+pub mod decorrelated_jitter;
+pub mod duration;
+pub mod error;
 pub mod exponential;
+pub mod fallback;
+pub mod fibonacci;
+pub mod hedge;
+#[cfg(feature = "reqwest")]
+pub mod http;
+mod instrument;
+pub mod limiter;
+pub mod linear;
+#[cfg(feature = "serde")]
+pub mod persist;
 pub mod retry;
+pub mod retry_stream;
+pub mod retry_with_context;
+pub mod sleep;
+pub mod supervise;
+#[cfg(feature = "tower")]
+pub mod tower;
@@ -0,0 +1,297 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use web_time::Instant;
+
+/// RetryLimiter caps the retries of a group of `Retry` futures,
+/// both how many retried attempts may be in flight and how many may start per second.
+///
+/// It's given to each retry by `Retry::with_limiter`, clones share the same limits.
+/// Only retries are limited, first attempts start at once. Retries are served
+/// in the order their backoff ended, so no caller is starved.
+#[derive(Debug, Clone, Default)]
+pub struct RetryLimiter {
+    inner: Arc<Mutex<LimiterState>>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    max_in_flight: Option<usize>,
+    interval: Option<Duration>,
+
+    in_flight: usize,
+    next_start: Option<Instant>,
+    queue: VecDeque<(u64, Option<Waker>)>,
+    next_ticket: u64,
+}
+
+impl LimiterState {
+    fn wake_front(&mut self) {
+        if let Some(waker) = self.queue.front_mut().and_then(|(_, w)| w.take()) {
+            waker.wake();
+        }
+    }
+}
+
+impl RetryLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Allow at most `max` retried attempts running at the same time.
+    pub fn with_max_in_flight(self, max: usize) -> Self {
+        debug_assert!(max > 0, "max in flight must be at least 1");
+        self.inner.lock().unwrap().max_in_flight = Some(max);
+        self
+    }
+    /// Allow at most `max` retried attempts to start per second, evenly spaced.
+    pub fn with_max_per_second(self, max: u32) -> Self {
+        debug_assert!(max > 0, "max per second must be at least 1");
+        self.inner.lock().unwrap().interval = Some(Duration::from_secs(1) / max);
+        self
+    }
+    /// Number of retried attempts running now.
+    pub fn in_flight(&self) -> usize {
+        self.inner.lock().unwrap().in_flight
+    }
+    /// Number of retries waiting for their turn.
+    pub fn waiting(&self) -> usize {
+        self.inner.lock().unwrap().queue.len()
+    }
+
+    /// Take a place at the end of the queue.
+    pub(crate) fn ticket(&self) -> Ticket {
+        let mut state = self.inner.lock().unwrap();
+        let id = state.next_ticket;
+        state.next_ticket += 1;
+        state.queue.push_back((id, None));
+        Ticket {
+            id,
+            limiter: self.clone(),
+        }
+    }
+}
+
+/// Ticket is a place in the queue of a `RetryLimiter`, it's given up on drop.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    id: u64,
+    limiter: RetryLimiter,
+}
+
+impl Ticket {
+    /// Get a permit once the ticket is first in the queue and an attempt may be started,
+    /// or the delay to wait when the rate limit is reached. `now` is the time of the retry's clock.
+    pub(crate) fn poll_acquire(
+        &self,
+        cx: &mut Context<'_>,
+        now: Instant,
+    ) -> Poll<Result<Permit, Duration>> {
+        let mut state = self.limiter.inner.lock().unwrap();
+        let full = state
+            .max_in_flight
+            .is_some_and(|max| state.in_flight >= max);
+        match state.queue.iter_mut().position(|(id, _)| *id == self.id) {
+            Some(0) if !full => {}
+            Some(pos) => {
+                state.queue[pos].1 = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            None => unreachable!("a ticket must stay queued until it's acquired"),
+        }
+        if let Some(interval) = state.interval {
+            let next = state.next_start.map_or(now, |next| next.max(now));
+            if next > now {
+                return Poll::Ready(Err(next - now));
+            }
+            state.next_start = Some(next + interval);
+        }
+        state.in_flight += 1;
+        state.queue.pop_front();
+        state.wake_front();
+        Poll::Ready(Ok(Permit {
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.limiter.inner.lock().unwrap();
+        if let Some(pos) = state.queue.iter().position(|(id, _)| *id == self.id) {
+            state.queue.remove(pos);
+            if pos == 0 {
+                state.wake_front();
+            }
+        }
+    }
+}
+
+/// Permit is held by a retried attempt while it runs.
+#[derive(Debug)]
+pub(crate) struct Permit {
+    limiter: RetryLimiter,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.inner.lock().unwrap();
+        state.in_flight -= 1;
+        state.wake_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use anyhow::Result;
+    use futures::future::join_all;
+
+    use crate::{
+        constant::ConstantBuilder,
+        retry::Retryable,
+        sleep::{Sleeper, TestClock},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_limiter_max_in_flight() -> Result<()> {
+        let limiter = RetryLimiter::new().with_max_in_flight(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let backoff = ConstantBuilder::default()
+            .with_delay(Duration::from_millis(1))
+            .with_max_times(2);
+
+        let retries = (0..10).map(|_| {
+            let (running, peak) = (running.clone(), peak.clone());
+            let mut calls = 0;
+            (move || {
+                calls += 1;
+                let first = calls == 1;
+                let (running, peak) = (running.clone(), peak.clone());
+                async move {
+                    if first {
+                        return Err(anyhow::anyhow!("retryable"));
+                    }
+                    let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(n, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .retry(&backoff)
+            .with_limiter(limiter.clone())
+        });
+        let results = join_all(retries).await;
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(2, peak.load(Ordering::SeqCst));
+        assert_eq!(0, limiter.in_flight());
+        assert_eq!(0, limiter.waiting());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limiter_max_per_second() -> Result<()> {
+        let limiter = RetryLimiter::new().with_max_per_second(100);
+        let backoff = ConstantBuilder::default()
+            .with_delay(Duration::from_millis(1))
+            .with_max_times(1);
+        let start = Instant::now();
+        let retries = (0..6).map(|_| {
+            let mut calls = 0;
+            (move || {
+                calls += 1;
+                let first = calls == 1;
+                async move {
+                    if first {
+                        return Err(anyhow::anyhow!("retryable"));
+                    }
+                    Ok(Instant::now())
+                }
+            })
+            .retry(&backoff)
+            .with_limiter(limiter.clone())
+        });
+        let mut starts = join_all(retries)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        starts.sort();
+        // the first retry starts at once, the next ones 10ms apart
+        assert!(starts[5] - start >= Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_limiter_with_test_clock() -> Result<()> {
+        let clock = TestClock::new();
+        let limiter = RetryLimiter::new().with_max_per_second(1);
+        let backoff = ConstantBuilder::default()
+            .with_delay(Duration::from_millis(1))
+            .with_max_times(1);
+        let retries = (0..3).map(|_| {
+            let mut calls = 0;
+            let sleeper = clock.clone();
+            (move || {
+                calls += 1;
+                let first = calls == 1;
+                let now = sleeper.now();
+                async move {
+                    if first {
+                        return Err(anyhow::anyhow!("retryable"));
+                    }
+                    Ok(now)
+                }
+            })
+            .retry(&backoff)
+            .sleep(clock.clone())
+            .with_limiter(limiter.clone())
+        });
+        let mut starts = join_all(retries)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        starts.sort();
+        // the retries wait for their turn on the virtual time, one second apart
+        assert!(starts[1] - starts[0] >= Duration::from_secs(1));
+        assert!(starts[2] - starts[1] >= Duration::from_secs(1));
+        assert!(clock.elapsed() < Duration::from_secs(3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limiter_fifo() {
+        let limiter = RetryLimiter::new().with_max_in_flight(1);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        let first = limiter.ticket();
+        let second = limiter.ticket();
+        let third = limiter.ticket();
+        assert!(second.poll_acquire(&mut cx, Instant::now()).is_pending());
+
+        let permit = first.poll_acquire(&mut cx, Instant::now());
+        assert!(matches!(permit, Poll::Ready(Ok(_))));
+        assert!(second.poll_acquire(&mut cx, Instant::now()).is_pending());
+        drop(permit);
+
+        // giving up a place lets the next ticket go first
+        drop(second);
+        assert!(matches!(
+            third.poll_acquire(&mut cx, Instant::now()),
+            Poll::Ready(Ok(_))
+        ));
+        assert_eq!(0, limiter.waiting());
+        assert_eq!(0, limiter.in_flight());
+    }
+}
@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use web_time::Instant;
+
+use crate::backoff::{Backoff, BackoffBuilder, BackoffState, DelayLimit, ResumableBuilder};
+#[derive(Debug, Clone)]
+pub struct LinearBuilder {
+    step: Duration,
+    min_delay: Duration,
+    max_delay: Option<Duration>,
+    max_times: Option<usize>,
+    limit: DelayLimit,
+}
+
+impl Default for LinearBuilder {
+    fn default() -> Self {
+        Self {
+            step: Duration::from_secs(1),
+            min_delay: Duration::from_secs(1),
+            max_delay: Some(Duration::from_secs(60)),
+            max_times: Some(3),
+            limit: DelayLimit::default(),
+        }
+    }
+}
+
+impl LinearBuilder {
+    /// The delay added to the previous delay on every retry.
+    pub fn with_step(mut self, step: Duration) -> Self {
+        self.step = step;
+        self
+    }
+    pub fn with_min_delay(mut self, min_delay: Duration) -> Self {
+        self.min_delay = min_delay;
+        self
+    }
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+    pub fn with_max_times(mut self, max_times: usize) -> Self {
+        self.max_times = Some(max_times);
+        self
+    }
+    /// Stop the backoff once the sum of delays would exceed `total_delay`,
+    /// the last delay is clipped to fit.
+    pub fn with_total_delay(mut self, total_delay: Duration) -> Self {
+        self.limit.total_delay = Some(total_delay);
+        self
+    }
+    /// Stop the backoff once a delay would end after `deadline`,
+    /// the last delay is clipped to fit.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.limit.deadline = Some(deadline);
+        self
+    }
+}
+
+impl BackoffBuilder for LinearBuilder {
+    type Backoff = LinearBackoff;
+    fn build(&self) -> Self::Backoff {
+        LinearBackoff {
+            step: self.step,
+            min_delay: self.min_delay,
+            max_delay: self.max_delay,
+            max_times: self.max_times,
+            limit: self.limit.clone(),
+
+            current_delay: None,
+            attempts: 0,
+        }
+    }
+}
+
+impl ResumableBuilder for LinearBuilder {
+    fn resume(&self, state: &BackoffState) -> LinearBackoff {
+        let mut backoff = self.build();
+        backoff.attempts = state.attempts;
+        backoff.current_delay = state.current_delay;
+        backoff.limit.resume(state.slept);
+        backoff
+    }
+    fn state(backoff: &LinearBackoff) -> BackoffState {
+        BackoffState {
+            attempts: backoff.attempts,
+            current_delay: backoff.current_delay,
+            slept: backoff.limit.slept(),
+            last_failure: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LinearBackoff {
+    step: Duration,
+    min_delay: Duration,
+    max_delay: Option<Duration>,
+    max_times: Option<usize>,
+    limit: DelayLimit,
+
+    current_delay: Option<Duration>,
+    attempts: usize,
+}
+
+impl LinearBackoff {
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempts >= self.max_times.unwrap_or(usize::MAX) {
+            return None;
+        }
+        self.attempts += 1;
+        let cur = match self.current_delay {
+            // first retry
+            None => self.min_delay,
+            Some(cur) => cur.saturating_add(self.step),
+        };
+        let cur = match self.max_delay {
+            Some(max_delay) => cur.min(max_delay),
+            None => cur,
+        };
+        self.current_delay = Some(cur);
+        Some(cur)
+    }
+}
+
+impl Iterator for LinearBackoff {
+    type Item = Duration;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(Instant::now())
+    }
+}
+
+impl Backoff for LinearBackoff {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let delay = self.next_delay();
+        self.limit.clip(now, delay)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        self.next_delay()?;
+        self.limit.clip(now, Some(delay))
+    }
+    fn deadline_reached(&self) -> bool {
+        self.limit.deadline_reached()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_linear_default() {
+        let mut linear = LinearBuilder::default().build();
+
+        assert_eq!(Some(Duration::from_secs(1)), linear.next());
+        assert_eq!(Some(Duration::from_secs(2)), linear.next());
+        assert_eq!(Some(Duration::from_secs(3)), linear.next());
+        assert_eq!(None, linear.next());
+    }
+    #[test]
+    fn test_linear_step() {
+        let mut linear = LinearBuilder::default()
+            .with_step(Duration::from_millis(500))
+            .build();
+
+        assert_eq!(Some(Duration::from_millis(1000)), linear.next());
+        assert_eq!(Some(Duration::from_millis(1500)), linear.next());
+        assert_eq!(Some(Duration::from_millis(2000)), linear.next());
+        assert_eq!(None, linear.next());
+    }
+    #[test]
+    fn test_linear_max_delay() {
+        let mut linear = LinearBuilder::default()
+            .with_max_times(4)
+            .with_max_delay(Duration::from_secs(2))
+            .build();
+
+        assert_eq!(Some(Duration::from_secs(1)), linear.next());
+        assert_eq!(Some(Duration::from_secs(2)), linear.next());
+        assert_eq!(Some(Duration::from_secs(2)), linear.next());
+        assert_eq!(Some(Duration::from_secs(2)), linear.next());
+        assert_eq!(None, linear.next());
+    }
+    #[test]
+    fn test_linear_max_times() {
+        let mut linear = LinearBuilder::default().with_max_times(1).build();
+
+        assert_eq!(Some(Duration::from_secs(1)), linear.next());
+        assert_eq!(None, linear.next());
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use web_time::Instant;
+
+use crate::backoff::{Backoff, BackoffBuilder, BackoffState, ResumableBuilder};
+
+/// FileStore keeps the state of backoffs in a JSON file, keyed by operation name.
+///
+/// The states are cached in memory and the changes are written by a background
+/// thread, so a retry never waits for the disk. The file is replaced atomically,
+/// and only the changed names are merged into it while holding a lock on
+/// `<path>.lock`, so processes sharing the file keep each other's states.
+/// A state saved by another process is only seen after reopening the store.
+/// Clones share the same cache, the pending changes are written when
+/// the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    shared: Arc<Shared>,
+    _writer: Arc<Writer>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    path: PathBuf,
+    state: Mutex<StoreState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct StoreState {
+    states: HashMap<String, BackoffState>,
+    // changes not written yet, `None` removes the name
+    pending: HashMap<String, Option<BackoffState>>,
+    // bumped on every change, `written` catches up once the changes are on disk
+    version: u64,
+    written: u64,
+    error: Option<io::Error>,
+    closed: bool,
+}
+
+/// Writer stops the background thread once the last clone of the store is dropped.
+#[derive(Debug)]
+struct Writer {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl FileStore {
+    /// Use the file at `path`, which is created on the first save.
+    /// An existing file must hold valid states.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let states = read_all(&path)?;
+        let shared = Arc::new(Shared {
+            path,
+            state: Mutex::new(StoreState {
+                states,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        });
+        let handle = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("retry-backon-store".to_string())
+                .spawn(move || shared.write_loop())?
+        };
+        Ok(Self {
+            _writer: Arc::new(Writer {
+                shared: shared.clone(),
+                handle: Some(handle),
+            }),
+            shared,
+        })
+    }
+    pub fn load(&self, name: &str) -> Option<BackoffState> {
+        self.shared.state.lock().unwrap().states.get(name).cloned()
+    }
+    pub fn save(&self, name: &str, state: &BackoffState) {
+        let mut store = self.shared.state.lock().unwrap();
+        store.states.insert(name.to_string(), state.clone());
+        store.pending.insert(name.to_string(), Some(state.clone()));
+        store.version += 1;
+        self.shared.changed.notify_all();
+    }
+    pub fn remove(&self, name: &str) {
+        let mut store = self.shared.state.lock().unwrap();
+        if store.states.remove(name).is_some() {
+            store.pending.insert(name.to_string(), None);
+            store.version += 1;
+            self.shared.changed.notify_all();
+        }
+    }
+    /// Wait until the changes made so far are written,
+    /// returns the error of the last failed write if any.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut store = self.shared.state.lock().unwrap();
+        let version = store.version;
+        while store.written < version {
+            store = self.shared.changed.wait(store).unwrap();
+        }
+        match store.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    fn write_loop(&self) {
+        let mut store = self.state.lock().unwrap();
+        loop {
+            if store.pending.is_empty() {
+                if store.closed {
+                    return;
+                }
+                store = self.changed.wait(store).unwrap();
+                continue;
+            }
+            let pending = std::mem::take(&mut store.pending);
+            let version = store.version;
+            drop(store);
+
+            let result = self.write(pending);
+            store = self.state.lock().unwrap();
+            store.written = version;
+            if let Err(err) = result {
+                store.error = Some(err);
+            }
+            self.changed.notify_all();
+        }
+    }
+
+    /// Merge the changes into the states on disk, which may have been changed
+    /// by another process since they were loaded.
+    fn write(&self, pending: HashMap<String, Option<BackoffState>>) -> io::Result<()> {
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path)?;
+        // held until the file is replaced, so another process can't merge into stale states
+        lock.lock()?;
+        let mut states = read_all(&self.path)?;
+        for (name, state) in pending {
+            match state {
+                Some(state) => states.insert(name, state),
+                None => states.remove(&name),
+            };
+        }
+        let data = serde_json::to_vec_pretty(&states)?;
+        // unique per process and per write, so concurrent writers never share a temp file
+        static TMP_ID: AtomicU64 = AtomicU64::new(0);
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            TMP_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)?;
+        lock.unlock()
+    }
+}
+
+fn read_all(path: &Path) -> io::Result<HashMap<String, BackoffState>> {
+    match fs::read(path) {
+        Ok(data) => {
+            serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Persisted builds backoffs which resume from the state saved under `name`
+/// and save their state in the store after every delay. The state is forgotten
+/// once the operation succeeds or the schedule is exhausted. The time of the
+/// last failure is taken from the retry's clock, and the delays already taken
+/// still count against the total delay of the builder after a restart.
+/// Saving is best effort, a failed write doesn't stop the retry
+/// and is reported by `FileStore::flush`.
+#[derive(Debug, Clone)]
+pub struct Persisted<B: ResumableBuilder> {
+    builder: B,
+    store: FileStore,
+    name: String,
+}
+
+impl<B: ResumableBuilder> Persisted<B> {
+    pub fn new(builder: B, store: FileStore, name: impl Into<String>) -> Self {
+        Self {
+            builder,
+            store,
+            name: name.into(),
+        }
+    }
+    /// The part of the last saved delay not slept yet,
+    /// to wait before the first attempt after a restart.
+    pub fn remaining_delay(&self) -> Duration {
+        self.store
+            .load(&self.name)
+            .map_or(Duration::ZERO, |state| state.remaining_delay())
+    }
+    /// Forget the saved state, e.g. after the config of the backoff changed.
+    pub fn reset(&self) {
+        self.store.remove(&self.name)
+    }
+}
+
+impl<B: ResumableBuilder> BackoffBuilder for Persisted<B> {
+    type Backoff = PersistedBackoff<B>;
+    fn build(&self) -> Self::Backoff {
+        let backoff = match self.store.load(&self.name) {
+            Some(state) => self.builder.resume(&state),
+            None => self.builder.build(),
+        };
+        PersistedBackoff {
+            backoff,
+            store: self.store.clone(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+pub struct PersistedBackoff<B: ResumableBuilder> {
+    backoff: B::Backoff,
+    store: FileStore,
+    name: String,
+}
+
+impl<B: ResumableBuilder> Iterator for PersistedBackoff<B> {
+    type Item = Duration;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_at(Instant::now())
+    }
+}
+
+impl<B: ResumableBuilder> Backoff for PersistedBackoff<B> {
+    fn next_at(&mut self, now: Instant) -> Option<Duration> {
+        let delay = self.backoff.next_at(now);
+        self.persist(now, delay)
+    }
+    fn next_after(&mut self, now: Instant, delay: Duration) -> Option<Duration> {
+        let delay = self.backoff.next_after(now, delay);
+        self.persist(now, delay)
+    }
+    fn deadline_reached(&self) -> bool {
+        self.backoff.deadline_reached()
+    }
+    fn succeeded(&mut self) {
+        self.store.remove(&self.name);
+    }
+}
+
+impl<B: ResumableBuilder> PersistedBackoff<B> {
+    /// Save the state after a delay, an exhausted schedule is forgotten
+    /// so the next run starts over instead of giving up at once.
+    fn persist(&mut self, now: Instant, delay: Option<Duration>) -> Option<Duration> {
+        match delay {
+            Some(_) => {
+                let mut state = B::state(&self.backoff);
+                state.failed_at(now);
+                self.store.save(&self.name, &state);
+            }
+            None => self.store.remove(&self.name),
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{
+        constant::ConstantBuilder,
+        decorrelated_jitter::DecorrelatedJitterBuilder,
+        exponential::ExponentialBuilder,
+        fibonacci::FibonacciBuilder,
+        retry::Retryable,
+        sleep::{Sleeper, TestClock},
+    };
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("retry-backon-{}-{name}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_store() -> Result<()> {
+        let path = temp_path("store");
+        let store = FileStore::open(&path)?;
+        assert_eq!(None, store.load("ingest"));
+
+        let state = BackoffState {
+            attempts: 2,
+            current_delay: Some(Duration::from_secs(2)),
+            slept: Duration::from_secs(3),
+            last_failure: Some(web_time::SystemTime::now()),
+        };
+        store.save("ingest", &state);
+        store.save("other", &BackoffState::default());
+        assert_eq!(Some(state.clone()), store.load("ingest"));
+        store.flush()?;
+        assert_eq!(Some(state.clone()), FileStore::open(&path)?.load("ingest"));
+
+        // another process sharing the file keeps the states it didn't change
+        let other = FileStore::open(&path)?;
+        other.remove("other");
+        store.save("ingest", &BackoffState::default());
+        other.flush()?;
+        store.flush()?;
+        let reopened = FileStore::open(&path)?;
+        assert_eq!(Some(BackoffState::default()), reopened.load("ingest"));
+        assert_eq!(None, reopened.load("other"));
+
+        fs::write(&path, "not json")?;
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            FileStore::open(&path).unwrap_err().kind()
+        );
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_store_shared() -> Result<()> {
+        let path = temp_path("shared");
+        let stores = [FileStore::open(&path)?, FileStore::open(&path)?];
+        // two writers merging into the same file at once don't drop each other's states
+        thread::scope(|scope| {
+            for (store, name) in stores.iter().zip(["a", "b"]) {
+                scope.spawn(move || {
+                    for attempts in 1..=20 {
+                        let state = BackoffState {
+                            attempts,
+                            ..Default::default()
+                        };
+                        store.save(name, &state);
+                        store.flush().unwrap();
+                    }
+                });
+            }
+        });
+        let reopened = FileStore::open(&path)?;
+        assert_eq!(Some(20), reopened.load("a").map(|state| state.attempts));
+        assert_eq!(Some(20), reopened.load("b").map(|state| state.attempts));
+        Ok(())
+    }
+
+    /// Take two delays, then resume from the saved state and take the rest.
+    fn split<B: ResumableBuilder>(builder: &B) -> (Vec<Duration>, Vec<Duration>) {
+        let mut backoff = builder.build();
+        let head: Vec<_> = backoff.by_ref().take(2).collect();
+        let tail = builder.resume(&B::state(&backoff)).collect();
+        (head, tail)
+    }
+
+    #[test]
+    fn test_resumable_builders() {
+        let builder = ConstantBuilder::default().with_max_times(5);
+        let (head, tail) = split(&builder);
+        assert_eq!(builder.build().collect::<Vec<_>>(), [head, tail].concat());
+
+        let builder = FibonacciBuilder::default().with_max_times(6);
+        let (head, tail) = split(&builder);
+        assert_eq!(builder.build().collect::<Vec<_>>(), [head, tail].concat());
+
+        let (head, tail) = split(&DecorrelatedJitterBuilder::default().with_max_times(5));
+        assert_eq!((2, 3), (head.len(), tail.len()));
+    }
+
+    #[test]
+    fn test_persisted_resumes_after_restart() -> Result<()> {
+        let path = temp_path("restart");
+        let builder = ExponentialBuilder::default();
+
+        // the first process dies after two delays
+        let store = FileStore::open(&path)?;
+        let persisted = Persisted::new(builder.clone(), store.clone(), "ingest");
+        let mut backoff = persisted.build();
+        assert_eq!(Some(Duration::from_secs(1)), backoff.next());
+        assert_eq!(Some(Duration::from_secs(2)), backoff.next());
+        store.flush()?;
+
+        // a restarted process with the same config continues the schedule
+        let persisted = Persisted::new(builder, FileStore::open(&path)?, "ingest");
+        assert!(persisted.remaining_delay() > Duration::from_secs(1));
+        let mut backoff = persisted.build();
+        assert_eq!(Some(Duration::from_secs(4)), backoff.next());
+
+        // the exhausted schedule is not kept, the next run starts over
+        assert_eq!(None, backoff.next());
+        assert_eq!(Duration::ZERO, persisted.remaining_delay());
+        assert_eq!(Some(Duration::from_secs(1)), persisted.build().next());
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_keeps_total_delay() -> Result<()> {
+        let path = temp_path("total");
+        let builder = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_total_delay(Duration::from_secs(5));
+
+        let store = FileStore::open(&path)?;
+        let mut backoff = Persisted::new(builder.clone(), store.clone(), "ingest").build();
+        assert_eq!(Some(Duration::from_secs(1)), backoff.next());
+        assert_eq!(Some(Duration::from_secs(2)), backoff.next());
+        store.flush()?;
+
+        // the restarted process only has what's left of the total delay
+        let mut backoff = Persisted::new(builder, FileStore::open(&path)?, "ingest").build();
+        assert_eq!(Some(Duration::from_secs(2)), backoff.next());
+        assert_eq!(None, backoff.next());
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_failure_time_from_clock() -> Result<()> {
+        let store = FileStore::open(temp_path("clock"))?;
+        let clock = TestClock::new();
+        clock.advance(Duration::from_secs(3600));
+
+        let persisted = Persisted::new(ExponentialBuilder::default(), store.clone(), "ingest");
+        persisted.build().next_at(Sleeper::now(&clock));
+        let last_failure = store
+            .load("ingest")
+            .and_then(|state| state.last_failure)
+            .expect("failure must be saved");
+        let ahead = last_failure.duration_since(web_time::SystemTime::now())?;
+        assert!(ahead > Duration::from_secs(3590), "{ahead:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_persisted_forgets_state() -> Result<()> {
+        let path = temp_path("forget");
+        let store = FileStore::open(&path)?;
+        let persisted = Persisted::new(ExponentialBuilder::default(), store.clone(), "ingest");
+        let clock = TestClock::new();
+
+        // a retry giving up leaves no state behind
+        let result = always_error.retry(&persisted).sleep(clock.clone()).await;
+        assert!(result.is_err());
+        assert_eq!(3, clock.sleeps().len());
+        assert_eq!(None, store.load("ingest"));
+
+        // nor does a retry which succeeds
+        let mut calls = 0;
+        let result = (|| {
+            calls += 1;
+            let n = calls;
+            async move {
+                match n {
+                    1 => Err(anyhow::anyhow!("upstream unavailable")),
+                    _ => Ok(n),
+                }
+            }
+        })
+        .retry(&persisted)
+        .sleep(clock)
+        .await;
+        assert_eq!(2, result?);
+        store.flush()?;
+        assert_eq!(None, FileStore::open(&path)?.load("ingest"));
+        Ok(())
+    }
+
+    async fn always_error() -> Result<()> {
+        Err(anyhow::anyhow!("upstream unavailable"))
+    }
+}
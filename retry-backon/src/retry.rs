@@ -1,5 +1,5 @@
 use std::{
-    future::Future,
+    future::{Future, Ready},
     pin::Pin,
     task::{ready, Poll},
     time::Duration,
@@ -7,7 +7,19 @@ use std::{
 
 use pin_project::pin_project;
 
-use crate::backoff::{Backoff, BackoffBuilder};
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    cancel::CancellationToken,
+    circuit_breaker::{CircuitBreaker, CircuitPermit},
+    error::{
+        AttemptTimeout, Cancelled, CircuitOpen, IntoCancelledError, IntoCircuitOpenError,
+        IntoTimeoutError, Report, RetryDecision, RetryError, StopReason,
+    },
+    fallback::{Fallback, FallbackValue},
+    instrument::Attempt,
+    limiter::{Permit, RetryLimiter, Ticket},
+    sleep::{DefaultSleeper, Sleeper},
+};
 
 pub trait Retryable<
     B: BackoffBuilder,
@@ -31,14 +43,53 @@ where
         Retry::new(self, builder.build())
     }
 }
+/// AsyncWhen decides asynchronously whether an error should be retried,
+/// it's implemented for `FnMut(&E) -> Future<Output = bool>`.
+pub trait AsyncWhen<E> {
+    type Fut: Future<Output = bool>;
+    fn check(&mut self, err: &E) -> Self::Fut;
+}
+
+/// impl AsyncWhen for F: FnMut(&E) -> Future<Output = bool>
+impl<E, F, Fut> AsyncWhen<E> for F
+where
+    F: FnMut(&E) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    type Fut = Fut;
+    fn check(&mut self, err: &E) -> Self::Fut {
+        self(err)
+    }
+}
+
 #[pin_project]
-pub struct Retry<B: Backoff, T, E, Fut: Future<Output = Result<T, E>>, FutureFn: FnMut() -> Fut> {
+pub struct Retry<
+    B: Backoff,
+    T,
+    E,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper = DefaultSleeper,
+    W: AsyncWhen<E> = fn(&E) -> Ready<bool>,
+> {
     backoff: B,
     retryable: fn(&E) -> bool,
+    retryable_async: Option<W>,
+    decide: fn(&E) -> RetryDecision,
     notify: fn(&E, Duration),
+    attempt_timeout: Option<(Duration, IntoTimeoutError<E>)>,
+    cancellation: Option<(CancellationToken, IntoCancelledError<E>)>,
+    abort_on_cancel: bool,
+    limiter: Option<RetryLimiter>,
+    ticket: Option<Ticket>,
+    permit: Option<Permit>,
+    circuit_breaker: Option<(CircuitBreaker, IntoCircuitOpenError<E>)>,
+    circuit_permit: Option<CircuitPermit>,
+    report: Report<E>,
     future_fn: FutureFn,
+    sleeper: SF,
     #[pin]
-    state: State<T, E, Fut>,
+    state: State<T, E, Fut, SF::Sleep, W::Fut>,
 }
 
 impl<B, T, E, Fut, FutureFn> Retry<B, T, E, Fut, FutureFn>
@@ -51,8 +102,53 @@ where
         Self {
             backoff,
             retryable: |_: &E| true,
+            retryable_async: None,
+            decide: |_: &E| RetryDecision::Retry,
             notify: |_: &E, _: Duration| {},
+            attempt_timeout: None,
+            cancellation: None,
+            abort_on_cancel: false,
+            limiter: None,
+            ticket: None,
+            permit: None,
+            circuit_breaker: None,
+            circuit_permit: None,
+            report: Report::new(),
             future_fn,
+            sleeper: DefaultSleeper::default(),
+            state: State::Idle,
+        }
+    }
+}
+
+impl<B, T, E, Fut, FutureFn, SF, W> Retry<B, T, E, Fut, FutureFn, SF, W>
+where
+    B: Backoff,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper,
+    W: AsyncWhen<E>,
+{
+    /// Replace the sleeper used to wait between attempts,
+    /// e.g. to run the retry on a runtime other than the default one.
+    pub fn sleep<SN: Sleeper>(self, sleeper: SN) -> Retry<B, T, E, Fut, FutureFn, SN, W> {
+        Retry {
+            backoff: self.backoff,
+            retryable: self.retryable,
+            retryable_async: self.retryable_async,
+            decide: self.decide,
+            notify: self.notify,
+            attempt_timeout: self.attempt_timeout,
+            cancellation: self.cancellation,
+            abort_on_cancel: self.abort_on_cancel,
+            limiter: self.limiter,
+            ticket: None,
+            permit: None,
+            circuit_breaker: self.circuit_breaker,
+            circuit_permit: None,
+            report: self.report,
+            future_fn: self.future_fn,
+            sleeper,
             state: State::Idle,
         }
     }
@@ -60,25 +156,412 @@ where
         self.retryable = retryable;
         self
     }
+    /// Decide asynchronously whether an error should be retried,
+    /// e.g. by checking a health endpoint. It's only called for errors accepted by `when`,
+    /// and the returned future can't borrow the error.
+    pub fn when_async<WN: AsyncWhen<E>>(
+        self,
+        retryable: WN,
+    ) -> Retry<B, T, E, Fut, FutureFn, SF, WN> {
+        Retry {
+            backoff: self.backoff,
+            retryable: self.retryable,
+            retryable_async: Some(retryable),
+            decide: self.decide,
+            notify: self.notify,
+            attempt_timeout: self.attempt_timeout,
+            cancellation: self.cancellation,
+            abort_on_cancel: self.abort_on_cancel,
+            limiter: self.limiter,
+            ticket: None,
+            permit: None,
+            circuit_breaker: self.circuit_breaker,
+            circuit_permit: None,
+            report: self.report,
+            future_fn: self.future_fn,
+            sleeper: self.sleeper,
+            state: State::Idle,
+        }
+    }
+    /// Decide how to retry an error accepted by `when`,
+    /// e.g. to wait for the delay asked by a rate-limited API.
+    pub fn decide(mut self, decide: fn(&E) -> RetryDecision) -> Self {
+        self.decide = decide;
+        self
+    }
     pub fn notify(mut self, notify: fn(&E, Duration)) -> Self {
         self.notify = notify;
         self
     }
+    /// Give up an attempt which is not finished after `timeout`.
+    /// The attempt is dropped and an `AttemptTimeout` converted into `E`
+    /// goes through `when` and `notify` like any other error.
+    pub fn with_attempt_timeout(mut self, timeout: Duration) -> Self
+    where
+        E: From<AttemptTimeout>,
+    {
+        self.attempt_timeout = Some((timeout, E::from));
+        self
+    }
+    /// Stop retrying once `token` is cancelled, a `Cancelled` converted into `E`
+    /// is returned if the retry was sleeping or not started yet.
+    /// An attempt in flight is awaited and its result returned as is,
+    /// unless `abort_on_cancel` is set.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self
+    where
+        E: From<Cancelled>,
+    {
+        self.cancellation = Some((token, E::from));
+        self
+    }
+    /// Drop the attempt in flight on cancel instead of waiting for it.
+    pub fn abort_on_cancel(mut self) -> Self {
+        self.abort_on_cancel = true;
+        self
+    }
+    /// Name the operation in the spans and metrics of the `tracing` and `metrics` features.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.report.instrument.name = name;
+        self
+    }
+    /// Wait for `limiter` before each retried attempt, and hold its permit while the attempt runs.
+    pub fn with_limiter(mut self, limiter: RetryLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+    /// Ask `breaker` before each attempt, and record the outcome of the attempts it lets through.
+    /// A rejected attempt fails with a `CircuitOpen` converted into `E`,
+    /// which goes through `when` and `notify` like any other error.
+    /// Errors rejected by `when` don't count as failures of the dependency.
+    ///
+    /// A rejection is not waited out, the retry sleeps for the next delay of its
+    /// backoff like after a failure. To wait for the rest of the cool-down instead,
+    /// return `RetryDecision::RetryAfter(open.0)` from `decide` for a `CircuitOpen`.
+    /// The cool-downs are measured on the sleeper's clock.
+    pub fn with_circuit_breaker(mut self, breaker: CircuitBreaker) -> Self
+    where
+        E: From<CircuitOpen>,
+    {
+        self.circuit_breaker = Some((breaker, E::from));
+        self
+    }
+    /// Resolve to `RetryError` on failure, which keeps every error met,
+    /// the number of attempts, the elapsed time and why the retry gave up.
+    pub fn with_report(mut self) -> ReportRetry<B, T, E, Fut, FutureFn, SF, W> {
+        self.report.collect = true;
+        ReportRetry { inner: self }
+    }
+    /// Call `fallback` with the final `RetryError` once the retry gives up,
+    /// its result is returned instead, e.g. to serve a stale cache entry.
+    pub fn fallback<FB: Fallback<T, E>>(
+        self,
+        fallback: FB,
+    ) -> FallbackRetry<B, T, E, Fut, FutureFn, FB, SF, W> {
+        FallbackRetry {
+            fallback: Some(fallback),
+            inner: self,
+            fallback_fut: None,
+        }
+    }
+    /// Return `Ok(value)` once the retry gives up.
+    pub fn fallback_value(
+        self,
+        value: T,
+    ) -> FallbackRetry<B, T, E, Fut, FutureFn, FallbackValue<T>, SF, W> {
+        self.fallback(FallbackValue(value))
+    }
+
+    fn poll_report(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<T, RetryError<E>>> {
+        let mut this = self.project();
+        loop {
+            let state = this.state.as_mut().project();
+            let err = match state {
+                StateProject::Idle => {
+                    if let Some((token, into_err)) = &this.cancellation {
+                        if token.is_cancelled() {
+                            let err = this.report.give_up(
+                                into_err(Cancelled),
+                                StopReason::Cancelled,
+                                this.sleeper.now(),
+                            );
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                    this.report.start(this.sleeper.now());
+                    if let Some((breaker, into_err)) = &this.circuit_breaker {
+                        match breaker.try_acquire(this.sleeper.now()) {
+                            Ok(permit) => *this.circuit_permit = Some(permit),
+                            Err(open) => {
+                                let err = into_err(open);
+                                if !(this.retryable)(&err) {
+                                    let err = this.report.give_up(
+                                        err,
+                                        StopReason::NotRetryable,
+                                        this.sleeper.now(),
+                                    );
+                                    return Poll::Ready(Err(err));
+                                }
+                                // skip the attempt, wait for the backoff like after a failure,
+                                // and let another retry of the limiter run meanwhile
+                                *this.permit = None;
+                                *this.ticket = None;
+                                this.state.set(State::Rejected(Some(err)));
+                                continue;
+                            }
+                        }
+                    }
+                    // a rejected attempt is not counted, it never reached the dependency
+                    this.report.start_attempt();
+                    let fut = this.report.instrument.in_span(|| (this.future_fn)());
+                    let fut = this.report.instrument.instrument(fut);
+                    let timer = this
+                        .attempt_timeout
+                        .map(|(timeout, _)| this.sleeper.timeout(timeout));
+                    this.state.set(State::Polling(fut, timer));
+                    continue;
+                }
+                StateProject::Polling(fut, timer) => {
+                    let result = match fut.poll(cx) {
+                        Poll::Ready(result) => result,
+                        Poll::Pending => {
+                            if let (true, Some((token, into_err))) =
+                                (*this.abort_on_cancel, &this.cancellation)
+                            {
+                                if token.poll_cancelled(cx).is_ready() {
+                                    let err = this.report.give_up(
+                                        into_err(Cancelled),
+                                        StopReason::Cancelled,
+                                        this.sleeper.now(),
+                                    );
+                                    this.state.set(State::Idle);
+                                    return Poll::Ready(Err(err));
+                                }
+                            }
+                            match (timer.as_pin_mut(), *this.attempt_timeout) {
+                                (Some(timer), Some((timeout, into_err))) => {
+                                    ready!(timer.poll(cx));
+                                    Err(into_err(AttemptTimeout(timeout)))
+                                }
+                                _ => return Poll::Pending,
+                            }
+                        }
+                    };
+                    // the attempt is over, let another retry of the limiter run
+                    *this.permit = None;
+                    if let Some(permit) = this.circuit_permit.take() {
+                        let healthy = match &result {
+                            Ok(_) => true,
+                            Err(err) => !(this.retryable)(err),
+                        };
+                        permit.record(healthy, this.sleeper.now());
+                    }
+                    let err = match result {
+                        Ok(v) => {
+                            this.backoff.succeeded();
+                            this.report.succeed();
+                            return Poll::Ready(Ok(v));
+                        }
+                        Err(err) => err,
+                    };
+                    if !(this.retryable)(&err) {
+                        let err =
+                            this.report
+                                .give_up(err, StopReason::NotRetryable, this.sleeper.now());
+                        return Poll::Ready(Err(err));
+                    }
+                    if let Some(retryable) = this.retryable_async {
+                        let fut = retryable.check(&err);
+                        this.state.set(State::Checking(fut, Some(err)));
+                        continue;
+                    }
+                    err
+                }
+                StateProject::Checking(fut, err) => {
+                    let retryable = ready!(fut.poll(cx));
+                    let err = err.take().expect("error must be kept while checking");
+                    if !retryable {
+                        let err =
+                            this.report
+                                .give_up(err, StopReason::NotRetryable, this.sleeper.now());
+                        return Poll::Ready(Err(err));
+                    }
+                    err
+                }
+                StateProject::Rejected(err) => {
+                    err.take().expect("error must be kept while rejected")
+                }
+                StateProject::Sleeping(sl) => {
+                    if let Some((token, into_err)) = &this.cancellation {
+                        if token.poll_cancelled(cx).is_ready() {
+                            let err = this.report.give_up(
+                                into_err(Cancelled),
+                                StopReason::Cancelled,
+                                this.sleeper.now(),
+                            );
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                    ready!(sl.poll(cx));
+                    match this.limiter {
+                        Some(limiter) => {
+                            *this.ticket = Some(limiter.ticket());
+                            this.state.set(State::Limiting(None));
+                        }
+                        None => this.state.set(State::Idle),
+                    }
+                    continue;
+                }
+                StateProject::Limiting(sl) => {
+                    if let Some((token, into_err)) = &this.cancellation {
+                        if token.poll_cancelled(cx).is_ready() {
+                            *this.ticket = None;
+                            let err = this.report.give_up(
+                                into_err(Cancelled),
+                                StopReason::Cancelled,
+                                this.sleeper.now(),
+                            );
+                            return Poll::Ready(Err(err));
+                        }
+                    }
+                    if let Some(sl) = sl.as_pin_mut() {
+                        ready!(sl.poll(cx));
+                        this.state.set(State::Limiting(None));
+                    }
+                    let ticket = this
+                        .ticket
+                        .as_ref()
+                        .expect("ticket must be kept while limiting");
+                    match ready!(ticket.poll_acquire(cx, this.sleeper.now())) {
+                        Ok(permit) => {
+                            *this.permit = Some(permit);
+                            *this.ticket = None;
+                            this.state.set(State::Idle);
+                        }
+                        Err(wait) => this
+                            .state
+                            .set(State::Limiting(Some(this.sleeper.sleep(wait)))),
+                    }
+                    continue;
+                }
+            };
+            let cancelled = this
+                .cancellation
+                .as_ref()
+                .is_some_and(|(token, _)| token.is_cancelled());
+            match this.report.next_delay(
+                err,
+                this.backoff,
+                *this.decide,
+                *this.notify,
+                cancelled,
+                this.sleeper.now(),
+            ) {
+                Ok(dur) => this.state.set(State::Sleeping(this.sleeper.sleep(dur))),
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
 }
 
 #[pin_project(project = StateProject)]
-enum State<T, E, Fut: Future<Output = Result<T, E>>> {
+enum State<
+    T,
+    E,
+    Fut: Future<Output = Result<T, E>>,
+    SleepFut: Future<Output = ()>,
+    CheckFut: Future<Output = bool>,
+> {
     Idle,
-    Polling(#[pin] Fut),
-    Sleeping(#[pin] Pin<Box<tokio::time::Sleep>>),
+    Polling(#[pin] Attempt<Fut>, #[pin] Option<SleepFut>),
+    Checking(#[pin] CheckFut, Option<E>),
+    Sleeping(#[pin] SleepFut),
+    Limiting(#[pin] Option<SleepFut>),
+    Rejected(Option<E>),
 }
 
 /// impl Future for Retry
-impl<B, T, E, Fut, FutureFn> Future for Retry<B, T, E, Fut, FutureFn>
+impl<B, T, E, Fut, FutureFn, SF, W> Future for Retry<B, T, E, Fut, FutureFn, SF, W>
+where
+    B: Backoff,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper,
+    W: AsyncWhen<E>,
+{
+    type Output = Result<T, E>;
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        self.poll_report(cx).map_err(RetryError::into_inner)
+    }
+}
+
+/// ReportRetry is a `Retry` resolving to `RetryError` on failure,
+/// created by `Retry::with_report`.
+#[pin_project]
+pub struct ReportRetry<
+    B: Backoff,
+    T,
+    E,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper = DefaultSleeper,
+    W: AsyncWhen<E> = fn(&E) -> Ready<bool>,
+> {
+    #[pin]
+    inner: Retry<B, T, E, Fut, FutureFn, SF, W>,
+}
+
+/// impl Future for ReportRetry
+impl<B, T, E, Fut, FutureFn, SF, W> Future for ReportRetry<B, T, E, Fut, FutureFn, SF, W>
+where
+    B: Backoff,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    SF: Sleeper,
+    W: AsyncWhen<E>,
+{
+    type Output = Result<T, RetryError<E>>;
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        self.project().inner.poll_report(cx)
+    }
+}
+/// FallbackRetry is a `Retry` calling a fallback when it gives up,
+/// created by `Retry::fallback` and `Retry::fallback_value`.
+#[pin_project]
+pub struct FallbackRetry<
+    B: Backoff,
+    T,
+    E,
+    Fut: Future<Output = Result<T, E>>,
+    FutureFn: FnMut() -> Fut,
+    FB: Fallback<T, E>,
+    SF: Sleeper = DefaultSleeper,
+    W: AsyncWhen<E> = fn(&E) -> Ready<bool>,
+> {
+    fallback: Option<FB>,
+    #[pin]
+    inner: Retry<B, T, E, Fut, FutureFn, SF, W>,
+    #[pin]
+    fallback_fut: Option<FB::Fut>,
+}
+
+/// impl Future for FallbackRetry
+impl<B, T, E, Fut, FutureFn, FB, SF, W> Future for FallbackRetry<B, T, E, Fut, FutureFn, FB, SF, W>
 where
     B: Backoff,
     Fut: Future<Output = Result<T, E>>,
     FutureFn: FnMut() -> Fut,
+    FB: Fallback<T, E>,
+    SF: Sleeper,
+    W: AsyncWhen<E>,
 {
     type Output = Result<T, E>;
     fn poll(
@@ -86,38 +569,19 @@ where
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
         let mut this = self.project();
-        loop {
-            let state = this.state.as_mut().project();
-            match state {
-                StateProject::Idle => {
-                    let fut = (this.future_fn)();
-                    this.state.set(State::Polling(fut));
-                    continue;
-                }
-                StateProject::Polling(fut) => match ready!(fut.poll(cx)) {
-                    Ok(v) => return Poll::Ready(Ok(v)),
-                    Err(err) => {
-                        if !(this.retryable)(&err) {
-                            return Poll::Ready(Err(err));
-                        }
-                        match this.backoff.next() {
-                            None => return Poll::Ready(Err(err)),
-                            Some(dur) => {
-                                (this.notify)(&err, dur);
-                                this.state
-                                    .set(State::Sleeping(Box::pin(tokio::time::sleep(dur))));
-                                continue;
-                            }
-                        }
-                    }
-                },
-                StateProject::Sleeping(sl) => {
-                    ready!(sl.poll(cx));
-                    this.state.set(State::Idle);
-                    continue;
+        if this.fallback_fut.is_none() {
+            match ready!(this.inner.poll_report(cx)) {
+                Ok(v) => return Poll::Ready(Ok(v)),
+                Err(err) => {
+                    let fallback = this.fallback.take().expect("fallback must be called once");
+                    this.fallback_fut.set(Some(fallback.call(err)));
                 }
             }
         }
+        this.fallback_fut
+            .as_pin_mut()
+            .expect("fallback future must be set")
+            .poll(cx)
     }
 }
 #[cfg(test)]
@@ -125,7 +589,10 @@ mod tests {
     use anyhow::Result;
     use tokio::sync::Mutex;
 
-    use crate::exponential::ExponentialBuilder;
+    use crate::{
+        exponential::ExponentialBuilder,
+        sleep::{ManualSleeper, TestClock},
+    };
 
     use super::*;
     async fn always_error() -> Result<()> {
@@ -177,6 +644,338 @@ mod tests {
         assert_eq!("retryable", result.unwrap_err().to_string());
         assert_eq!(*error_times.lock().await, 4);
 
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_sleeper() -> Result<()> {
+        let sleeper = ManualSleeper::new();
+        let handle = tokio::spawn(
+            always_error
+                .retry(&ExponentialBuilder::default())
+                .sleep(sleeper.clone()),
+        );
+        while !handle.is_finished() {
+            tokio::task::yield_now().await;
+            sleeper.advance(Duration::from_secs(1));
+        }
+        let result = handle.await?;
+        assert!(result.is_err());
+        assert_eq!(
+            sleeper.sleeps(),
+            vec![
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(4)
+            ]
+        );
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_attempt_timeout() -> Result<()> {
+        let attempts = Mutex::new(0);
+        let f = || async {
+            let mut x = attempts.lock().await;
+            *x += 1;
+            if *x == 1 {
+                drop(x);
+                std::future::pending::<()>().await;
+            }
+            Ok::<_, anyhow::Error>("done")
+        };
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let result = f
+            .retry(&backoff)
+            .with_attempt_timeout(Duration::from_millis(10))
+            .when(|e| e.is::<AttemptTimeout>())
+            .await;
+        assert_eq!("done", result?);
+        assert_eq!(*attempts.lock().await, 2);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_attempt_timeout_exhausted() -> Result<()> {
+        let f = || std::future::pending::<Result<()>>();
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let result = f
+            .retry(&backoff)
+            .with_attempt_timeout(Duration::from_millis(10))
+            .await;
+        let err = result.unwrap_err();
+        assert_eq!(
+            Some(&AttemptTimeout(Duration::from_millis(10))),
+            err.downcast_ref::<AttemptTimeout>()
+        );
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_cancellation_while_sleeping() -> Result<()> {
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(
+            always_error
+                .retry(&ExponentialBuilder::default().with_min_delay(Duration::from_secs(60)))
+                .with_cancellation(token.clone()),
+        );
+        tokio::task::yield_now().await;
+        token.cancel();
+        let err = handle.await?.unwrap_err();
+        assert!(err.is::<Cancelled>());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_cancellation_while_polling() -> Result<()> {
+        let token = CancellationToken::new();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let rx = Mutex::new(Some(rx));
+        let f = || async {
+            let rx = rx.lock().await.take();
+            if let Some(rx) = rx {
+                rx.await?;
+            }
+            Err::<(), anyhow::Error>(anyhow::anyhow!("finished after cancel"))
+        };
+        let retry = f
+            .retry(&ExponentialBuilder::default())
+            .with_cancellation(token.clone());
+        tokio::pin!(retry);
+        assert!(poll_once(retry.as_mut()).await.is_none());
+
+        token.cancel();
+        tx.send(()).unwrap();
+        let err = retry.await.unwrap_err();
+        assert_eq!("finished after cancel", err.to_string());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_abort_on_cancel() -> Result<()> {
+        let token = CancellationToken::new();
+        let f = || std::future::pending::<Result<()>>();
+        let handle = tokio::spawn(
+            f.retry(&ExponentialBuilder::default())
+                .with_cancellation(token.clone())
+                .abort_on_cancel(),
+        );
+        tokio::task::yield_now().await;
+        token.cancel();
+        let err = handle.await?.unwrap_err();
+        assert!(err.is::<Cancelled>());
+
+        Ok(())
+    }
+    /// Poll a future once, returns its output if ready.
+    async fn poll_once<F: Future + Unpin>(mut fut: F) -> Option<F::Output> {
+        std::future::poll_fn(|cx| match Pin::new(&mut fut).poll(cx) {
+            Poll::Ready(v) => Poll::Ready(Some(v)),
+            Poll::Pending => Poll::Ready(None),
+        })
+        .await
+    }
+    #[tokio::test]
+    async fn test_retry_with_report() -> Result<()> {
+        let f = || async { Err::<(), std::io::Error>(std::io::Error::other("not retryable")) };
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let err = f
+            .retry(&backoff)
+            .when(|_| false)
+            .with_report()
+            .await
+            .unwrap_err();
+        assert_eq!(StopReason::NotRetryable, err.reason());
+        assert_eq!(1, err.attempts());
+        assert!(err.errors().is_empty());
+        assert_eq!(
+            "not retryable",
+            std::error::Error::source(&err).unwrap().to_string()
+        );
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_report_deadline() -> Result<()> {
+        let clock = TestClock::new();
+        let backoff = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_deadline(clock.now() + Duration::from_secs(3));
+        let err = always_error
+            .retry(&backoff)
+            .sleep(clock.clone())
+            .with_report()
+            .await
+            .unwrap_err();
+        // the deadline is checked on the virtual time
+        clock.assert_sleeps(&[Duration::from_secs(1), Duration::from_secs(2)]);
+        assert_eq!(StopReason::Deadline, err.reason());
+        assert_eq!(3, err.attempts());
+        assert_eq!(Duration::from_secs(3), err.elapsed());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_when_async() -> Result<()> {
+        let error_times = Mutex::new(0);
+        let f = || async {
+            let mut x = error_times.lock().await;
+            *x += 1;
+            Err::<(), anyhow::Error>(anyhow::anyhow!("error {x}"))
+        };
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let result = f
+            .retry(&backoff)
+            .when_async(|e: &anyhow::Error| {
+                let retryable = e.to_string() != "error 2";
+                async move {
+                    tokio::task::yield_now().await;
+                    retryable
+                }
+            })
+            .await;
+        assert_eq!("error 2", result.unwrap_err().to_string());
+        assert_eq!(*error_times.lock().await, 2);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_fallback() -> Result<()> {
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let result = always_error
+            .retry(&backoff)
+            .fallback(|err: RetryError<anyhow::Error>| async move {
+                assert_eq!(4, err.attempts());
+                assert_eq!("test_query meets error", err.last_error().to_string());
+                Ok(())
+            })
+            .await;
+        assert!(result.is_ok());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_fallback_value() -> Result<()> {
+        let backoff = ExponentialBuilder::default().with_min_delay(Duration::from_millis(1));
+        let f = || async { Err::<&str, anyhow::Error>(anyhow::anyhow!("not retryable")) };
+        let result = f
+            .retry(&backoff)
+            .when(|_| false)
+            .fallback_value("stale")
+            .await;
+        assert_eq!("stale", result?);
+
+        let f = || async { Ok::<&str, anyhow::Error>("fresh") };
+        let result = f.retry(&backoff).fallback_value("stale").await;
+        assert_eq!("fresh", result?);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_test_clock() -> Result<()> {
+        let clock = TestClock::new();
+        let err = always_error
+            .retry(&ExponentialBuilder::default())
+            .sleep(clock.clone())
+            .with_report()
+            .await
+            .unwrap_err();
+        clock.assert_sleeps(&[
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+            Duration::from_secs(4),
+        ]);
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!(4, err.attempts());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_test_clock_attempt_timeout() -> Result<()> {
+        let clock = TestClock::new();
+        let mut calls = 0;
+        let f = || {
+            calls += 1;
+            let first = calls == 1;
+            async move {
+                if first {
+                    std::future::pending::<()>().await;
+                }
+                Ok::<_, anyhow::Error>("done")
+            }
+        };
+        let retry = f
+            .retry(&ExponentialBuilder::default())
+            .sleep(clock.clone())
+            .with_attempt_timeout(Duration::from_secs(10));
+        tokio::pin!(retry);
+        // the timeout waits for the virtual time instead of firing at once
+        assert!(poll_once(retry.as_mut()).await.is_none());
+        clock.advance(Duration::from_secs(9));
+        assert!(poll_once(retry.as_mut()).await.is_none());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!("done", retry.await?);
+        clock.assert_sleeps(&[Duration::from_secs(1)]);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_decide() -> Result<()> {
+        #[derive(Debug)]
+        enum ApiError {
+            RateLimited(Duration),
+            Unavailable,
+            Invalid,
+        }
+        let clock = TestClock::new();
+        let mut errors = vec![
+            ApiError::Invalid,
+            ApiError::Unavailable,
+            ApiError::RateLimited(Duration::from_secs(30)),
+        ];
+        let f = || {
+            let err = errors.pop().unwrap();
+            async { Err::<(), _>(err) }
+        };
+        let err = f
+            .retry(&ExponentialBuilder::default())
+            .sleep(clock.clone())
+            .decide(|e| match e {
+                ApiError::RateLimited(after) => RetryDecision::RetryAfter(*after),
+                ApiError::Unavailable => RetryDecision::Retry,
+                ApiError::Invalid => RetryDecision::Stop,
+            })
+            .with_report()
+            .await
+            .unwrap_err();
+        // the override takes the first step, the next error gets the second delay
+        clock.assert_sleeps(&[Duration::from_secs(30), Duration::from_secs(2)]);
+        assert_eq!(StopReason::NotRetryable, err.reason());
+        assert!(matches!(err.last_error(), ApiError::Invalid));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_total_delay() -> Result<()> {
+        let clock = TestClock::new();
+        let backoff = ExponentialBuilder::default()
+            .with_max_times(10)
+            .with_total_delay(Duration::from_secs(10));
+        let err = always_error
+            .retry(&backoff)
+            .sleep(clock.clone())
+            .decide(|_| RetryDecision::RetryAfter(Duration::from_secs(4)))
+            .with_report()
+            .await
+            .unwrap_err();
+        // the overrides are clipped by the total delay of the builder
+        clock.assert_sleeps(&[
+            Duration::from_secs(4),
+            Duration::from_secs(4),
+            Duration::from_secs(2),
+        ]);
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!(4, err.attempts());
+
         Ok(())
     }
 }
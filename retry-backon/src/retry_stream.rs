@@ -0,0 +1,275 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use web_time::Instant;
+
+use futures_core::Stream;
+use pin_project::pin_project;
+
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    sleep::{DefaultSleeper, Sleeper},
+};
+
+pub trait StreamRetryable<
+    B: BackoffBuilder,
+    T,
+    E,
+    S: Stream<Item = Result<T, E>>,
+    StreamFn: FnMut() -> S,
+>
+{
+    fn retry_stream(self, builder: &B) -> RetryStream<B, T, E, S, StreamFn>;
+}
+
+/// impl StreamRetryable for StreamFn: FnMut()->Stream<Item = Result<T, E>>
+impl<B, T, E, S, StreamFn> StreamRetryable<B, T, E, S, StreamFn> for StreamFn
+where
+    B: BackoffBuilder,
+    S: Stream<Item = Result<T, E>>,
+    StreamFn: FnMut() -> S,
+{
+    fn retry_stream(self, builder: &B) -> RetryStream<B, T, E, S, StreamFn> {
+        RetryStream::new(self, builder.clone())
+    }
+}
+
+/// RetryStream forwards the items of the stream created by `stream_fn`,
+/// and creates a new one after a delay when it yields a retryable error.
+/// Errors which are not retried are forwarded, then the stream ends.
+#[pin_project]
+pub struct RetryStream<
+    B: BackoffBuilder,
+    T,
+    E,
+    S: Stream<Item = Result<T, E>>,
+    StreamFn: FnMut() -> S,
+    SF: Sleeper = DefaultSleeper,
+> {
+    builder: B,
+    backoff: B::Backoff,
+    retryable: fn(&E) -> bool,
+    notify: fn(&E, Duration),
+    reset_after: Option<Duration>,
+    healthy_since: Option<Instant>,
+    stream_fn: StreamFn,
+    sleeper: SF,
+    #[pin]
+    state: State<S, SF::Sleep>,
+}
+
+impl<B, T, E, S, StreamFn> RetryStream<B, T, E, S, StreamFn>
+where
+    B: BackoffBuilder,
+    S: Stream<Item = Result<T, E>>,
+    StreamFn: FnMut() -> S,
+{
+    fn new(stream_fn: StreamFn, builder: B) -> Self {
+        Self {
+            backoff: builder.build(),
+            builder,
+            retryable: |_: &E| true,
+            notify: |_: &E, _: Duration| {},
+            reset_after: None,
+            healthy_since: None,
+            stream_fn,
+            sleeper: DefaultSleeper::default(),
+            state: State::Idle,
+        }
+    }
+}
+
+impl<B, T, E, S, StreamFn, SF> RetryStream<B, T, E, S, StreamFn, SF>
+where
+    B: BackoffBuilder,
+    S: Stream<Item = Result<T, E>>,
+    StreamFn: FnMut() -> S,
+    SF: Sleeper,
+{
+    /// Replace the sleeper used to wait before creating a new stream.
+    pub fn sleep<SN: Sleeper>(self, sleeper: SN) -> RetryStream<B, T, E, S, StreamFn, SN> {
+        RetryStream {
+            builder: self.builder,
+            backoff: self.backoff,
+            retryable: self.retryable,
+            notify: self.notify,
+            reset_after: self.reset_after,
+            healthy_since: self.healthy_since,
+            stream_fn: self.stream_fn,
+            sleeper,
+            state: State::Idle,
+        }
+    }
+    pub fn when(mut self, retryable: fn(&E) -> bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+    pub fn notify(mut self, notify: fn(&E, Duration)) -> Self {
+        self.notify = notify;
+        self
+    }
+    /// Start the backoff over once the stream has been yielding
+    /// only `Ok` items for `period`.
+    pub fn with_reset_after(mut self, period: Duration) -> Self {
+        self.reset_after = Some(period);
+        self
+    }
+}
+
+#[pin_project(project = StateProject)]
+enum State<S, SleepFut> {
+    Idle,
+    Streaming(#[pin] S),
+    Sleeping(#[pin] SleepFut),
+    Done,
+}
+
+/// impl Stream for RetryStream
+impl<B, T, E, S, StreamFn, SF> Stream for RetryStream<B, T, E, S, StreamFn, SF>
+where
+    B: BackoffBuilder,
+    S: Stream<Item = Result<T, E>>,
+    StreamFn: FnMut() -> S,
+    SF: Sleeper,
+{
+    type Item = Result<T, E>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        loop {
+            let state = this.state.as_mut().project();
+            match state {
+                StateProject::Idle => {
+                    let stream = (this.stream_fn)();
+                    this.state.set(State::Streaming(stream));
+                }
+                StateProject::Streaming(stream) => match ready!(stream.poll_next(cx)) {
+                    None => {
+                        this.state.set(State::Done);
+                        return Poll::Ready(None);
+                    }
+                    Some(Ok(v)) => {
+                        if let Some(reset_after) = this.reset_after {
+                            let now = this.sleeper.now();
+                            let since = *this.healthy_since.get_or_insert(now);
+                            if now.saturating_duration_since(since) >= *reset_after {
+                                *this.backoff = this.builder.build();
+                                *this.healthy_since = None;
+                            }
+                        }
+                        return Poll::Ready(Some(Ok(v)));
+                    }
+                    Some(Err(err)) => {
+                        *this.healthy_since = None;
+                        if !(this.retryable)(&err) {
+                            this.state.set(State::Done);
+                            return Poll::Ready(Some(Err(err)));
+                        }
+                        match this.backoff.next_at(this.sleeper.now()) {
+                            None => {
+                                this.state.set(State::Done);
+                                return Poll::Ready(Some(Err(err)));
+                            }
+                            Some(dur) => {
+                                (this.notify)(&err, dur);
+                                this.state.set(State::Sleeping(this.sleeper.sleep(dur)));
+                            }
+                        }
+                    }
+                },
+                StateProject::Sleeping(sl) => {
+                    ready!(sl.poll(cx));
+                    this.state.set(State::Idle);
+                }
+                StateProject::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use futures::{stream, StreamExt};
+
+    use crate::constant::ConstantBuilder;
+
+    use super::*;
+
+    fn results(items: Vec<Result<i32>>) -> Vec<String> {
+        items
+            .into_iter()
+            .map(|v| match v {
+                Ok(v) => v.to_string(),
+                Err(e) => e.to_string(),
+            })
+            .collect()
+    }
+    #[tokio::test]
+    async fn test_retry_stream() -> Result<()> {
+        let mut subscriptions = 0;
+        let f = move || {
+            subscriptions += 1;
+            stream::iter(match subscriptions {
+                1 => vec![Ok(1), Ok(2), Err(anyhow::anyhow!("disconnected"))],
+                _ => vec![Ok(3)],
+            })
+        };
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let items = f.retry_stream(&backoff).collect::<Vec<_>>().await;
+        assert_eq!(vec!["1", "2", "3"], results(items));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_stream_exhausted() -> Result<()> {
+        let mut subscriptions = 0;
+        let f = || {
+            subscriptions += 1;
+            stream::iter(vec![Err::<i32, _>(anyhow::anyhow!("disconnected"))])
+        };
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let items = f.retry_stream(&backoff).collect::<Vec<_>>().await;
+        assert_eq!(vec!["disconnected"], results(items));
+        assert_eq!(4, subscriptions);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_stream_not_retryable() -> Result<()> {
+        let f = || stream::iter(vec![Ok(1), Err(anyhow::anyhow!("fatal")), Ok(2)]);
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let items = f
+            .retry_stream(&backoff)
+            .when(|e| e.to_string() != "fatal")
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec!["1", "fatal"], results(items));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_stream_reset_after() -> Result<()> {
+        let mut subscriptions = 0;
+        let f = move || {
+            subscriptions += 1;
+            stream::iter(match subscriptions {
+                1..=3 => vec![Ok(subscriptions), Err(anyhow::anyhow!("disconnected"))],
+                _ => vec![],
+            })
+        };
+        let backoff = ConstantBuilder::default()
+            .with_delay(Duration::from_millis(1))
+            .with_max_times(1);
+        let items = f
+            .retry_stream(&backoff)
+            .with_reset_after(Duration::ZERO)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(vec!["1", "2", "3"], results(items));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    future::{Future, Ready},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use pin_project::pin_project;
+
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    cancel::CancellationToken,
+    error::{Cancelled, IntoCancelledError, Report, RetryDecision, RetryError, StopReason},
+    instrument::Attempt,
+    sleep::{DefaultSleeper, Sleeper},
+};
+
+pub trait RetryableWithContext<
+    B: BackoffBuilder,
+    T,
+    E,
+    Ctx,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+>
+{
+    fn retry_with(
+        self,
+        ctx: Ctx,
+        builder: &B,
+    ) -> RetryWithContext<B::Backoff, T, E, Ctx, Fut, FutureFn>;
+}
+
+/// impl RetryableWithContext for FutureFn: FnMut(Ctx)->Future<Output = (Ctx, Result<T, E>)>
+impl<B, T, E, Ctx, Fut, FutureFn> RetryableWithContext<B, T, E, Ctx, Fut, FutureFn> for FutureFn
+where
+    B: BackoffBuilder,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+{
+    fn retry_with(
+        self,
+        ctx: Ctx,
+        builder: &B,
+    ) -> RetryWithContext<B::Backoff, T, E, Ctx, Fut, FutureFn> {
+        RetryWithContext::new(self, ctx, builder.build())
+    }
+}
+
+/// Repair fixes the context between two attempts, e.g. reconnects a client,
+/// it's implemented for `FnMut(Ctx) -> Future<Output = Ctx>`.
+pub trait Repair<Ctx> {
+    type Fut: Future<Output = Ctx>;
+    fn repair(&mut self, ctx: Ctx) -> Self::Fut;
+}
+
+/// impl Repair for F: FnMut(Ctx) -> Future<Output = Ctx>
+impl<Ctx, F, Fut> Repair<Ctx> for F
+where
+    F: FnMut(Ctx) -> Fut,
+    Fut: Future<Output = Ctx>,
+{
+    type Fut = Fut;
+    fn repair(&mut self, ctx: Ctx) -> Self::Fut {
+        self(ctx)
+    }
+}
+
+/// RetryWithContext hands a context to each attempt, which gives it back
+/// along with its result, so an attempt can use `&mut` state without locks
+/// and repair it for the next attempt, e.g. reconnect a client.
+/// It resolves to the context and the last result.
+///
+/// The context can't be taken back from an attempt in flight,
+/// so there is no attempt timeout nor abort on cancel.
+#[pin_project]
+pub struct RetryWithContext<
+    B: Backoff,
+    T,
+    E,
+    Ctx,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+    SF: Sleeper = DefaultSleeper,
+    R: Repair<Ctx> = fn(Ctx) -> Ready<Ctx>,
+> {
+    backoff: B,
+    retryable: fn(&E) -> bool,
+    decide: fn(&E) -> RetryDecision,
+    notify: fn(&E, Duration),
+    cancellation: Option<(CancellationToken, IntoCancelledError<E>)>,
+    repair: Option<R>,
+    report: Report<E>,
+    ctx: Option<Ctx>,
+    future_fn: FutureFn,
+    sleeper: SF,
+    #[pin]
+    state: State<Fut, SF::Sleep, R::Fut>,
+}
+
+impl<B, T, E, Ctx, Fut, FutureFn> RetryWithContext<B, T, E, Ctx, Fut, FutureFn>
+where
+    B: Backoff,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+{
+    fn new(future_fn: FutureFn, ctx: Ctx, backoff: B) -> Self {
+        Self {
+            backoff,
+            retryable: |_: &E| true,
+            decide: |_: &E| RetryDecision::Retry,
+            notify: |_: &E, _: Duration| {},
+            cancellation: None,
+            repair: None,
+            report: Report::new(),
+            ctx: Some(ctx),
+            future_fn,
+            sleeper: DefaultSleeper::default(),
+            state: State::Idle,
+        }
+    }
+}
+
+impl<B, T, E, Ctx, Fut, FutureFn, SF, R> RetryWithContext<B, T, E, Ctx, Fut, FutureFn, SF, R>
+where
+    B: Backoff,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+    SF: Sleeper,
+    R: Repair<Ctx>,
+{
+    /// Replace the sleeper used to wait between attempts.
+    pub fn sleep<SN: Sleeper>(
+        self,
+        sleeper: SN,
+    ) -> RetryWithContext<B, T, E, Ctx, Fut, FutureFn, SN, R> {
+        RetryWithContext {
+            backoff: self.backoff,
+            retryable: self.retryable,
+            decide: self.decide,
+            notify: self.notify,
+            cancellation: self.cancellation,
+            repair: self.repair,
+            report: self.report,
+            ctx: self.ctx,
+            future_fn: self.future_fn,
+            sleeper,
+            state: State::Idle,
+        }
+    }
+    pub fn when(mut self, retryable: fn(&E) -> bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+    /// Decide how to retry an error accepted by `when`,
+    /// e.g. to wait for the delay asked by a rate-limited API.
+    pub fn decide(mut self, decide: fn(&E) -> RetryDecision) -> Self {
+        self.decide = decide;
+        self
+    }
+    pub fn notify(mut self, notify: fn(&E, Duration)) -> Self {
+        self.notify = notify;
+        self
+    }
+    /// Repair the context after each delay, before the next attempt.
+    pub fn repair<RN: Repair<Ctx>>(
+        self,
+        repair: RN,
+    ) -> RetryWithContext<B, T, E, Ctx, Fut, FutureFn, SF, RN> {
+        RetryWithContext {
+            backoff: self.backoff,
+            retryable: self.retryable,
+            decide: self.decide,
+            notify: self.notify,
+            cancellation: self.cancellation,
+            repair: Some(repair),
+            report: self.report,
+            ctx: self.ctx,
+            future_fn: self.future_fn,
+            sleeper: self.sleeper,
+            state: State::Idle,
+        }
+    }
+    /// Stop retrying once `token` is cancelled, a `Cancelled` converted into `E`
+    /// is returned with the context if the retry was sleeping or not started yet.
+    /// An attempt in flight is awaited and its result returned as is.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self
+    where
+        E: From<Cancelled>,
+    {
+        self.cancellation = Some((token, E::from));
+        self
+    }
+    /// Name the operation in the spans and metrics of the `tracing` and `metrics` features.
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.report.instrument.name = name;
+        self
+    }
+    /// Resolve to `RetryError` on failure, which keeps every error met,
+    /// the number of attempts, the elapsed time and why the retry gave up.
+    pub fn with_report(mut self) -> ReportRetryWithContext<B, T, E, Ctx, Fut, FutureFn, SF, R> {
+        self.report.collect = true;
+        ReportRetryWithContext { inner: self }
+    }
+
+    fn poll_report(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<(Ctx, Result<T, RetryError<E>>)> {
+        let mut this = self.project();
+        loop {
+            match this.state.as_mut().project() {
+                StateProject::Idle => {
+                    let ctx = this
+                        .ctx
+                        .take()
+                        .expect("RetryWithContext polled after completion");
+                    if let Some((token, into_err)) = &this.cancellation {
+                        if token.is_cancelled() {
+                            let err = this.report.give_up(
+                                into_err(Cancelled),
+                                StopReason::Cancelled,
+                                this.sleeper.now(),
+                            );
+                            return Poll::Ready((ctx, Err(err)));
+                        }
+                    }
+                    this.report.start(this.sleeper.now());
+                    this.report.start_attempt();
+                    let fut = this.report.instrument.in_span(|| (this.future_fn)(ctx));
+                    let fut = this.report.instrument.instrument(fut);
+                    this.state.set(State::Polling(fut));
+                }
+                StateProject::Polling(fut) => {
+                    let (ctx, result) = ready!(fut.poll(cx));
+                    this.state.set(State::Idle);
+                    let err = match result {
+                        Ok(v) => {
+                            this.backoff.succeeded();
+                            this.report.succeed();
+                            return Poll::Ready((ctx, Ok(v)));
+                        }
+                        Err(err) => err,
+                    };
+                    let now = this.sleeper.now();
+                    if !(this.retryable)(&err) {
+                        let err = this.report.give_up(err, StopReason::NotRetryable, now);
+                        return Poll::Ready((ctx, Err(err)));
+                    }
+                    let cancelled = this
+                        .cancellation
+                        .as_ref()
+                        .is_some_and(|(token, _)| token.is_cancelled());
+                    match this.report.next_delay(
+                        err,
+                        this.backoff,
+                        *this.decide,
+                        *this.notify,
+                        cancelled,
+                        now,
+                    ) {
+                        Ok(dur) => {
+                            *this.ctx = Some(ctx);
+                            this.state.set(State::Sleeping(this.sleeper.sleep(dur)));
+                        }
+                        Err(err) => return Poll::Ready((ctx, Err(err))),
+                    }
+                }
+                StateProject::Sleeping(sl) => {
+                    if let Some((token, into_err)) = &this.cancellation {
+                        if token.poll_cancelled(cx).is_ready() {
+                            let ctx = this
+                                .ctx
+                                .take()
+                                .expect("context must be kept while sleeping");
+                            let err = this.report.give_up(
+                                into_err(Cancelled),
+                                StopReason::Cancelled,
+                                this.sleeper.now(),
+                            );
+                            this.state.set(State::Idle);
+                            return Poll::Ready((ctx, Err(err)));
+                        }
+                    }
+                    ready!(sl.poll(cx));
+                    match this.repair {
+                        Some(repair) => {
+                            let ctx = this
+                                .ctx
+                                .take()
+                                .expect("context must be kept while sleeping");
+                            this.state.set(State::Repairing(repair.repair(ctx)));
+                        }
+                        None => this.state.set(State::Idle),
+                    }
+                }
+                StateProject::Repairing(fut) => {
+                    let ctx = ready!(fut.poll(cx));
+                    *this.ctx = Some(ctx);
+                    this.state.set(State::Idle);
+                }
+            }
+        }
+    }
+}
+
+#[pin_project(project = StateProject)]
+enum State<Fut: Future, SleepFut, RepairFut> {
+    Idle,
+    Polling(#[pin] Attempt<Fut>),
+    Sleeping(#[pin] SleepFut),
+    Repairing(#[pin] RepairFut),
+}
+
+/// impl Future for RetryWithContext
+impl<B, T, E, Ctx, Fut, FutureFn, SF, R> Future
+    for RetryWithContext<B, T, E, Ctx, Fut, FutureFn, SF, R>
+where
+    B: Backoff,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+    SF: Sleeper,
+    R: Repair<Ctx>,
+{
+    type Output = (Ctx, Result<T, E>);
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.poll_report(cx)
+            .map(|(ctx, result)| (ctx, result.map_err(RetryError::into_inner)))
+    }
+}
+
+/// ReportRetryWithContext is a `RetryWithContext` resolving to `RetryError` on failure,
+/// created by `RetryWithContext::with_report`.
+#[pin_project]
+pub struct ReportRetryWithContext<
+    B: Backoff,
+    T,
+    E,
+    Ctx,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+    SF: Sleeper = DefaultSleeper,
+    R: Repair<Ctx> = fn(Ctx) -> Ready<Ctx>,
+> {
+    #[pin]
+    inner: RetryWithContext<B, T, E, Ctx, Fut, FutureFn, SF, R>,
+}
+
+/// impl Future for ReportRetryWithContext
+impl<B, T, E, Ctx, Fut, FutureFn, SF, R> Future
+    for ReportRetryWithContext<B, T, E, Ctx, Fut, FutureFn, SF, R>
+where
+    B: Backoff,
+    Fut: Future<Output = (Ctx, Result<T, E>)>,
+    FutureFn: FnMut(Ctx) -> Fut,
+    SF: Sleeper,
+    R: Repair<Ctx>,
+{
+    type Output = (Ctx, Result<T, RetryError<E>>);
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project().inner.poll_report(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{exponential::ExponentialBuilder, sleep::TestClock};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Connection {
+        broken: bool,
+        reconnects: usize,
+        sent: Vec<String>,
+    }
+
+    impl Connection {
+        async fn send(&mut self, msg: &str) -> Result<usize> {
+            if self.broken {
+                return Err(anyhow::anyhow!("connection reset"));
+            }
+            self.sent.push(msg.to_string());
+            Ok(self.sent.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_with_context() -> Result<()> {
+        let clock = TestClock::new();
+        let conn = Connection {
+            broken: true,
+            ..Default::default()
+        };
+        let (conn, result) = (|mut conn: Connection| async move {
+            let result = conn.send("hello").await;
+            (conn, result)
+        })
+        .retry_with(conn, &ExponentialBuilder::default())
+        .sleep(clock.clone())
+        .repair(|mut conn: Connection| async move {
+            // reconnect before the next attempt
+            conn.reconnects += 1;
+            conn.broken = conn.reconnects < 2;
+            conn
+        })
+        .await;
+        assert_eq!(1, result?);
+        assert_eq!(2, conn.reconnects);
+        assert_eq!(vec!["hello"], conn.sent);
+        clock.assert_sleeps(&[Duration::from_secs(1), Duration::from_secs(2)]);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_context_report() -> Result<()> {
+        let (conn, result) = (|mut conn: Connection| async move {
+            let result = conn.send("hello").await;
+            (conn, result)
+        })
+        .retry_with(
+            Connection {
+                broken: true,
+                ..Default::default()
+            },
+            &ExponentialBuilder::default(),
+        )
+        .sleep(TestClock::new())
+        .decide(|_| RetryDecision::RetryAfter(Duration::from_secs(5)))
+        .with_report()
+        .await;
+        let err = result.unwrap_err();
+        assert_eq!(StopReason::Exhausted, err.reason());
+        assert_eq!(4, err.attempts());
+        assert_eq!(3, err.errors().len());
+        assert_eq!(Duration::from_secs(15), err.elapsed());
+        assert!(conn.broken);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_context_cancellation() -> Result<()> {
+        let token = CancellationToken::new();
+        let handle = tokio::spawn(
+            (|mut conn: Connection| async move {
+                let result = conn.send("hello").await;
+                (conn, result)
+            })
+            .retry_with(
+                Connection {
+                    broken: true,
+                    ..Default::default()
+                },
+                &ExponentialBuilder::default().with_min_delay(Duration::from_secs(60)),
+            )
+            .with_cancellation(token.clone()),
+        );
+        tokio::task::yield_now().await;
+        token.cancel();
+        // the context is given back along with the cancellation
+        let (conn, result) = handle.await?;
+        assert!(result.unwrap_err().is::<Cancelled>());
+        assert!(conn.broken);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_with_borrowed_context() -> Result<()> {
+        let mut conn = Connection {
+            broken: true,
+            ..Default::default()
+        };
+        async fn send_hello(conn: &mut Connection) -> (&mut Connection, Result<usize>) {
+            let result = conn.send("hello").await;
+            (conn, result)
+        }
+        let (conn_ref, result) = send_hello
+            .retry_with(&mut conn, &ExponentialBuilder::default())
+            .sleep(TestClock::new())
+            .when(|e| e.to_string() != "connection reset")
+            .await;
+        assert_eq!("connection reset", result.unwrap_err().to_string());
+        conn_ref.broken = false;
+        assert!(conn.sent.is_empty());
+        assert!(!conn.broken);
+
+        Ok(())
+    }
+}
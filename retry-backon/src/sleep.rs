@@ -0,0 +1,358 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+use web_time::{Instant, SystemTime};
+
+use crate::cancel::CancellationToken;
+
+/// Sleeper is used by `Retry` to wait between two attempts,
+/// so the crate is not tied to a specific async runtime.
+pub trait Sleeper {
+    type Sleep: Future<Output = ()>;
+    fn sleep(&self, dur: Duration) -> Self::Sleep;
+    /// The time of the clock behind the sleeper, used for deadlines,
+    /// elapsed times and rate limits.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    /// Wait `dur` to time out an attempt. Unlike `sleep` it's not a delay,
+    /// so a virtual clock waits for its time to be moved instead of moving it.
+    fn timeout(&self, dur: Duration) -> Self::Sleep {
+        self.sleep(dur)
+    }
+}
+
+/// The wall-clock time matching `now` of a sleeper's clock,
+/// so a virtual clock gives virtual dates too.
+pub(crate) fn system_time(now: Instant) -> SystemTime {
+    let (wall, real) = (SystemTime::now(), Instant::now());
+    let time = match now.checked_duration_since(real) {
+        Some(ahead) => wall.checked_add(ahead),
+        None => wall.checked_sub(real.duration_since(now)),
+    };
+    time.unwrap_or(wall)
+}
+
+/// impl Sleeper for Fn(Duration) -> Future<Output = ()>
+impl<F, Fut> Sleeper for F
+where
+    F: Fn(Duration) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    type Sleep = Fut;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        self(dur)
+    }
+}
+
+/// BlockingSleeper is used by `BlockingRetry` to wait between two attempts.
+pub trait BlockingSleeper {
+    fn sleep(&self, dur: Duration);
+    /// The time of the clock behind the sleeper, used for deadlines and elapsed times.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    /// Sleep for `dur` unless `token` is cancelled first,
+    /// returns whether the token is cancelled.
+    fn sleep_cancellable(&self, dur: Duration, token: &CancellationToken) -> bool {
+        self.sleep(dur);
+        token.is_cancelled()
+    }
+}
+
+/// impl BlockingSleeper for Fn(Duration)
+impl<F: Fn(Duration)> BlockingSleeper for F {
+    fn sleep(&self, dur: Duration) {
+        self(dur)
+    }
+}
+
+/// ThreadSleeper blocks the current thread, it's the default of `BlockingRetry`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadSleeper;
+
+impl BlockingSleeper for ThreadSleeper {
+    fn sleep(&self, dur: Duration) {
+        thread::sleep(dur)
+    }
+    fn sleep_cancellable(&self, dur: Duration, token: &CancellationToken) -> bool {
+        token.wait_timeout(dur)
+    }
+}
+
+/// The sleeper used by `Retryable::retry`, picked from the enabled features
+/// in the order: tokio, async-std, smol, gloo-timers.
+#[cfg(feature = "tokio")]
+pub type DefaultSleeper = TokioSleeper;
+#[cfg(all(not(feature = "tokio"), feature = "async-std"))]
+pub type DefaultSleeper = AsyncStdSleeper;
+#[cfg(all(not(feature = "tokio"), not(feature = "async-std"), feature = "smol"))]
+pub type DefaultSleeper = SmolSleeper;
+#[cfg(all(
+    not(feature = "tokio"),
+    not(feature = "async-std"),
+    not(feature = "smol"),
+    feature = "gloo-timers"
+))]
+pub type DefaultSleeper = GlooSleeper;
+// Retry needs a default sleeper, a runtime feature has to pick one
+#[cfg(not(any(
+    feature = "tokio",
+    feature = "async-std",
+    feature = "smol",
+    feature = "gloo-timers"
+)))]
+compile_error!(
+    "retry-backon needs a runtime to sleep between retries, enable one of the `tokio`, `async-std`, `smol` or `gloo-timers` features"
+);
+
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioSleeper;
+
+#[cfg(feature = "tokio")]
+impl Sleeper for TokioSleeper {
+    type Sleep = tokio::time::Sleep;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        tokio::time::sleep(dur)
+    }
+}
+
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdSleeper;
+
+#[cfg(feature = "async-std")]
+impl Sleeper for AsyncStdSleeper {
+    type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        Box::pin(async_std::task::sleep(dur))
+    }
+}
+
+#[cfg(feature = "smol")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmolSleeper;
+
+#[cfg(feature = "smol")]
+impl Sleeper for SmolSleeper {
+    type Sleep = SmolSleep;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        SmolSleep(smol::Timer::after(dur))
+    }
+}
+
+#[cfg(feature = "smol")]
+pub struct SmolSleep(smol::Timer);
+
+#[cfg(feature = "smol")]
+impl Future for SmolSleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|_| ())
+    }
+}
+
+/// GlooSleeper waits with the browser's `setTimeout`, for wasm32-unknown-unknown
+/// where tokio timers are not available. Build with
+/// `default-features = false, features = ["gloo-timers"]` to make it the default.
+#[cfg(feature = "gloo-timers")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlooSleeper;
+
+#[cfg(feature = "gloo-timers")]
+impl Sleeper for GlooSleeper {
+    type Sleep = gloo_timers::future::TimeoutFuture;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        gloo_timers::future::sleep(dur)
+    }
+}
+
+/// ManualSleeper never looks at the real clock,
+/// its sleeps only complete when the virtual time is moved by `advance`.
+/// Clones share the same virtual clock, which makes it handy in tests.
+#[derive(Debug, Clone)]
+pub struct ManualSleeper {
+    origin: Instant,
+    inner: Arc<Mutex<ManualClock>>,
+}
+
+impl Default for ManualSleeper {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            inner: Arc::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ManualClock {
+    now: Duration,
+    sleeps: Vec<Duration>,
+    wakers: Vec<Waker>,
+}
+
+impl ManualSleeper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Move the virtual time forward and wake up the pending sleeps.
+    pub fn advance(&self, dur: Duration) {
+        let wakers = {
+            let mut clock = self.inner.lock().unwrap();
+            clock.now = clock.now.saturating_add(dur);
+            std::mem::take(&mut clock.wakers)
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+    /// Virtual time elapsed since the sleeper was created.
+    pub fn elapsed(&self) -> Duration {
+        self.inner.lock().unwrap().now
+    }
+    /// All the durations requested so far, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.inner.lock().unwrap().sleeps.clone()
+    }
+
+    fn record(&self, dur: Duration) {
+        self.inner.lock().unwrap().sleeps.push(dur);
+    }
+}
+
+impl Sleeper for ManualSleeper {
+    type Sleep = ManualSleep;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        self.record(dur);
+        self.timeout(dur)
+    }
+    fn now(&self) -> Instant {
+        self.origin + self.elapsed()
+    }
+    fn timeout(&self, dur: Duration) -> Self::Sleep {
+        ManualSleep {
+            deadline: self.elapsed().saturating_add(dur),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// ManualSleep completes once the virtual time of its `ManualSleeper`
+/// or `TestClock` reaches its deadline.
+pub struct ManualSleep {
+    deadline: Duration,
+    inner: Arc<Mutex<ManualClock>>,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut clock = self.inner.lock().unwrap();
+        if clock.now >= self.deadline {
+            return Poll::Ready(());
+        }
+        clock.wakers.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// TestClock is a virtual clock for tests, its sleeps complete at once
+/// and move the virtual time forward by the requested delay.
+///
+/// It's honoured by both `Retry` and `BlockingRetry`, so the delay schedule
+/// can be asserted without waiting. Deadlines, elapsed times and rate limits
+/// are measured on the virtual time too. An attempt timeout only fires once
+/// the virtual time is moved past it, by the sleeps of other retries or by `advance`.
+/// Clones share the same clock.
+#[derive(Debug, Clone, Default)]
+pub struct TestClock {
+    // the same virtual clock, only the sleeps move it by themselves
+    clock: ManualSleeper,
+}
+
+impl TestClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Virtual time elapsed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+    /// All the durations slept so far, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.clock.sleeps()
+    }
+    /// Panic unless exactly `expected` was slept, in order.
+    #[track_caller]
+    pub fn assert_sleeps(&self, expected: &[Duration]) {
+        let sleeps = self.sleeps();
+        assert_eq!(
+            expected,
+            sleeps.as_slice(),
+            "expected sleeps {expected:?}, got {sleeps:?}"
+        );
+    }
+    /// Move the virtual time forward without sleeping, e.g. to fire an attempt timeout.
+    pub fn advance(&self, dur: Duration) {
+        self.clock.advance(dur);
+    }
+
+    fn slept(&self, dur: Duration) {
+        self.clock.record(dur);
+        self.clock.advance(dur);
+    }
+}
+
+impl Sleeper for TestClock {
+    type Sleep = ManualSleep;
+    fn sleep(&self, dur: Duration) -> Self::Sleep {
+        self.slept(dur);
+        self.clock.timeout(Duration::ZERO)
+    }
+    fn now(&self) -> Instant {
+        Sleeper::now(&self.clock)
+    }
+    fn timeout(&self, dur: Duration) -> Self::Sleep {
+        self.clock.timeout(dur)
+    }
+}
+
+impl BlockingSleeper for TestClock {
+    fn sleep(&self, dur: Duration) {
+        self.slept(dur);
+    }
+    fn now(&self) -> Instant {
+        Sleeper::now(&self.clock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_manual_sleeper() {
+        let sleeper = ManualSleeper::new();
+        let sl = sleeper.sleep(Duration::from_secs(2));
+        let handle = tokio::spawn(sl);
+
+        sleeper.advance(Duration::from_secs(1));
+        tokio::task::yield_now().await;
+        assert!(!handle.is_finished());
+
+        sleeper.advance(Duration::from_secs(1));
+        handle.await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(2)]);
+        assert_eq!(sleeper.elapsed(), Duration::from_secs(2));
+
+        sleeper.advance(Duration::MAX);
+        sleeper.advance(Duration::MAX);
+        assert_eq!(sleeper.elapsed(), Duration::MAX);
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use web_time::Instant;
+
+use pin_project::pin_project;
+
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    cancel::CancellationToken,
+    sleep::{DefaultSleeper, Sleeper},
+};
+
+/// Run the task created by `task_fn` until it returns `Ok`,
+/// restarting it after the delays of `builder` when it fails.
+///
+/// The supervisor never gives up by itself: once the backoff is exhausted
+/// it starts over with a fresh one from `builder`. It only ends when the task
+/// succeeds or the supervisor is stopped by its handle. The error is returned
+/// if `builder` gives no delay at all, since the restarts couldn't be paced.
+pub fn supervise<B, E, Fut, TaskFn>(task_fn: TaskFn, builder: &B) -> Supervisor<B, E, Fut, TaskFn>
+where
+    B: BackoffBuilder,
+    Fut: Future<Output = Result<(), E>>,
+    TaskFn: FnMut() -> Fut,
+{
+    Supervisor {
+        backoff: builder.build(),
+        builder: builder.clone(),
+        notify: |_: &E, _: Duration| {},
+        stability_window: Duration::from_secs(60),
+        handle: SupervisorHandle::default(),
+        task_fn,
+        sleeper: DefaultSleeper::default(),
+        state: State::Idle,
+    }
+}
+
+/// SupervisorHandle reads the restart count of a `Supervisor` and stops it.
+#[derive(Debug, Clone, Default)]
+pub struct SupervisorHandle {
+    restarts: Arc<AtomicUsize>,
+    token: CancellationToken,
+}
+
+impl SupervisorHandle {
+    /// The number of times the task has been restarted.
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::Relaxed)
+    }
+    /// Stop the supervisor, the running task is dropped and the supervisor resolves to `Ok`.
+    pub fn stop(&self) {
+        self.token.cancel();
+    }
+}
+
+#[pin_project]
+pub struct Supervisor<
+    B: BackoffBuilder,
+    E,
+    Fut: Future<Output = Result<(), E>>,
+    TaskFn: FnMut() -> Fut,
+    SF: Sleeper = DefaultSleeper,
+> {
+    builder: B,
+    backoff: B::Backoff,
+    notify: fn(&E, Duration),
+    stability_window: Duration,
+    handle: SupervisorHandle,
+    task_fn: TaskFn,
+    sleeper: SF,
+    #[pin]
+    state: State<Fut, SF::Sleep>,
+}
+
+impl<B, E, Fut, TaskFn, SF> Supervisor<B, E, Fut, TaskFn, SF>
+where
+    B: BackoffBuilder,
+    Fut: Future<Output = Result<(), E>>,
+    TaskFn: FnMut() -> Fut,
+    SF: Sleeper,
+{
+    /// Replace the sleeper used to wait before a restart.
+    pub fn sleep<SN: Sleeper>(self, sleeper: SN) -> Supervisor<B, E, Fut, TaskFn, SN> {
+        Supervisor {
+            builder: self.builder,
+            backoff: self.backoff,
+            notify: self.notify,
+            stability_window: self.stability_window,
+            handle: self.handle,
+            task_fn: self.task_fn,
+            sleeper,
+            state: State::Idle,
+        }
+    }
+    pub fn notify(mut self, notify: fn(&E, Duration)) -> Self {
+        self.notify = notify;
+        self
+    }
+    /// A run lasting at least `window` is considered stable,
+    /// so the backoff starts over when it fails. Defaults to 60 seconds.
+    pub fn with_stability_window(mut self, window: Duration) -> Self {
+        self.stability_window = window;
+        self
+    }
+    pub fn handle(&self) -> SupervisorHandle {
+        self.handle.clone()
+    }
+}
+
+#[pin_project(project = StateProject)]
+enum State<Fut, SleepFut> {
+    Idle,
+    Running(#[pin] Fut, Instant),
+    Sleeping(#[pin] SleepFut),
+}
+
+/// impl Future for Supervisor
+impl<B, E, Fut, TaskFn, SF> Future for Supervisor<B, E, Fut, TaskFn, SF>
+where
+    B: BackoffBuilder,
+    Fut: Future<Output = Result<(), E>>,
+    TaskFn: FnMut() -> Fut,
+    SF: Sleeper,
+{
+    type Output = Result<(), E>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        if this.handle.token.poll_cancelled(cx).is_ready() {
+            this.state.set(State::Idle);
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            let state = this.state.as_mut().project();
+            match state {
+                StateProject::Idle => {
+                    let fut = (this.task_fn)();
+                    this.state.set(State::Running(fut, this.sleeper.now()));
+                }
+                StateProject::Running(fut, started) => {
+                    let err = match ready!(fut.poll(cx)) {
+                        Ok(()) => return Poll::Ready(Ok(())),
+                        Err(err) => err,
+                    };
+                    let now = this.sleeper.now();
+                    if now.saturating_duration_since(*started) >= *this.stability_window {
+                        *this.backoff = this.builder.build();
+                    }
+                    let next = this.backoff.next_at(now).or_else(|| {
+                        *this.backoff = this.builder.build();
+                        this.backoff.next_at(now)
+                    });
+                    match next {
+                        None => return Poll::Ready(Err(err)),
+                        Some(dur) => {
+                            (this.notify)(&err, dur);
+                            this.handle.restarts.fetch_add(1, Ordering::Relaxed);
+                            this.state.set(State::Sleeping(this.sleeper.sleep(dur)));
+                        }
+                    }
+                }
+                StateProject::Sleeping(sl) => {
+                    ready!(sl.poll(cx));
+                    this.state.set(State::Idle);
+                }
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::{constant::ConstantBuilder, sleep::TestClock};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_supervise() -> Result<()> {
+        let mut runs = 0;
+        let task = || {
+            runs += 1;
+            let failed = runs < 3;
+            async move {
+                if failed {
+                    return Err(anyhow::anyhow!("worker crashed"));
+                }
+                Ok(())
+            }
+        };
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let supervisor = supervise(task, &backoff);
+        let handle = supervisor.handle();
+        supervisor.await?;
+        assert_eq!(2, handle.restarts());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_supervise_exhausted() -> Result<()> {
+        let mut runs = 0;
+        let task = || {
+            runs += 1;
+            let failed = runs < 6;
+            async move {
+                if failed {
+                    return Err(anyhow::anyhow!("worker crashed"));
+                }
+                Ok(())
+            }
+        };
+        // the backoff only has two delays, it starts over instead of giving up
+        let clock = TestClock::new();
+        let backoff = ConstantBuilder::default()
+            .with_delay(Duration::from_secs(1))
+            .with_max_times(2);
+        let supervisor = supervise(task, &backoff).sleep(clock.clone());
+        let handle = supervisor.handle();
+        supervisor.await?;
+        assert_eq!(5, handle.restarts());
+        clock.assert_sleeps(&[Duration::from_secs(1); 5]);
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_supervise_no_delay() -> Result<()> {
+        let task = || async { Err::<(), _>(anyhow::anyhow!("worker crashed")) };
+        let backoff = ConstantBuilder::default().with_max_times(0);
+        let supervisor = supervise(task, &backoff);
+        let handle = supervisor.handle();
+        let err = supervisor.await.unwrap_err();
+        assert_eq!("worker crashed", err.to_string());
+        assert_eq!(0, handle.restarts());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_supervise_stability_window() -> Result<()> {
+        let mut runs = 0;
+        let task = || {
+            runs += 1;
+            let failed = runs < 5;
+            async move {
+                if failed {
+                    return Err(anyhow::anyhow!("worker crashed"));
+                }
+                Ok(())
+            }
+        };
+        let backoff = ConstantBuilder::default()
+            .with_delay(Duration::from_millis(1))
+            .with_max_times(1);
+        let supervisor = supervise(task, &backoff).with_stability_window(Duration::ZERO);
+        let handle = supervisor.handle();
+        supervisor.await?;
+        assert_eq!(4, handle.restarts());
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_supervise_stop() -> Result<()> {
+        let task = || std::future::pending::<Result<()>>();
+        let supervisor = supervise(task, &ConstantBuilder::default());
+        let handle = supervisor.handle();
+        let join = tokio::spawn(supervisor);
+        tokio::task::yield_now().await;
+        handle.stop();
+        join.await??;
+        assert_eq!(0, handle.restarts());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use pin_project::pin_project;
+use tower::{Layer, Service};
+
+use crate::{
+    backoff::{Backoff, BackoffBuilder},
+    sleep::{DefaultSleeper, Sleeper},
+};
+
+/// RetryLayer wraps a service into a `RetryService`, which calls it again
+/// with a clone of the request after the delays of `builder`
+/// as long as `policy` returns true for the result.
+#[derive(Debug, Clone)]
+pub struct RetryLayer<B: BackoffBuilder, P, SF = DefaultSleeper> {
+    builder: B,
+    policy: P,
+    sleeper: SF,
+}
+
+impl<B: BackoffBuilder, P> RetryLayer<B, P> {
+    pub fn new(builder: B, policy: P) -> Self {
+        Self {
+            builder,
+            policy,
+            sleeper: DefaultSleeper::default(),
+        }
+    }
+}
+
+impl<B: BackoffBuilder, P, SF> RetryLayer<B, P, SF> {
+    /// Replace the sleeper used to wait between attempts.
+    pub fn sleep<SN: Sleeper + Clone>(self, sleeper: SN) -> RetryLayer<B, P, SN> {
+        RetryLayer {
+            builder: self.builder,
+            policy: self.policy,
+            sleeper,
+        }
+    }
+}
+
+impl<S, B: BackoffBuilder, P: Clone, SF: Clone> Layer<S> for RetryLayer<B, P, SF> {
+    type Service = RetryService<S, B, P, SF>;
+    fn layer(&self, inner: S) -> Self::Service {
+        RetryService {
+            inner,
+            builder: self.builder.clone(),
+            policy: self.policy.clone(),
+            sleeper: self.sleeper.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryService<S, B: BackoffBuilder, P, SF = DefaultSleeper> {
+    inner: S,
+    builder: B,
+    policy: P,
+    sleeper: SF,
+}
+
+impl<S, B, P, SF, Req> Service<Req> for RetryService<S, B, P, SF>
+where
+    S: Service<Req> + Clone,
+    Req: Clone,
+    B: BackoffBuilder,
+    P: Fn(&Result<S::Response, S::Error>) -> bool + Clone,
+    SF: Sleeper + Clone,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S, Req, B::Backoff, P, SF>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        // keep the service which has been driven to readiness for this call
+        let clone = self.inner.clone();
+        let mut service = std::mem::replace(&mut self.inner, clone);
+        let fut = service.call(request.clone());
+        ResponseFuture {
+            service,
+            request,
+            backoff: self.builder.build(),
+            policy: self.policy.clone(),
+            sleeper: self.sleeper.clone(),
+            state: State::Calling(fut),
+        }
+    }
+}
+
+#[pin_project]
+pub struct ResponseFuture<S: Service<Req>, Req, B: Backoff, P, SF: Sleeper> {
+    service: S,
+    request: Req,
+    backoff: B,
+    policy: P,
+    sleeper: SF,
+    #[pin]
+    state: State<S::Future, SF::Sleep>,
+}
+
+#[pin_project(project = StateProject)]
+enum State<Fut, SleepFut> {
+    Calling(#[pin] Fut),
+    Sleeping(#[pin] SleepFut),
+    Ready,
+}
+
+/// impl Future for ResponseFuture
+impl<S, Req, B, P, SF> Future for ResponseFuture<S, Req, B, P, SF>
+where
+    S: Service<Req>,
+    Req: Clone,
+    B: Backoff,
+    P: Fn(&Result<S::Response, S::Error>) -> bool,
+    SF: Sleeper,
+{
+    type Output = Result<S::Response, S::Error>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+        loop {
+            match this.state.as_mut().project() {
+                StateProject::Calling(fut) => {
+                    let result = ready!(fut.poll(cx));
+                    if !(this.policy)(&result) {
+                        return Poll::Ready(result);
+                    }
+                    match this.backoff.next_at(this.sleeper.now()) {
+                        None => return Poll::Ready(result),
+                        Some(dur) => this.state.set(State::Sleeping(this.sleeper.sleep(dur))),
+                    }
+                }
+                StateProject::Sleeping(sl) => {
+                    ready!(sl.poll(cx));
+                    this.state.set(State::Ready);
+                }
+                StateProject::Ready => {
+                    ready!(this.service.poll_ready(cx))?;
+                    let fut = this.service.call(this.request.clone());
+                    this.state.set(State::Calling(fut));
+                }
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use tower::{service_fn, ServiceBuilder, ServiceExt};
+
+    use crate::constant::ConstantBuilder;
+
+    use super::*;
+
+    fn flaky_service(
+        calls: Arc<AtomicUsize>,
+        failures: usize,
+    ) -> impl Service<String, Response = String, Error = anyhow::Error, Future = impl Send> + Clone
+    {
+        service_fn(move |req: String| {
+            let n = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                if n <= failures {
+                    return Err(anyhow::anyhow!("unavailable"));
+                }
+                Ok(format!("hello, {req}!"))
+            }
+        })
+    }
+    #[tokio::test]
+    async fn test_retry_layer() -> Result<()> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let service = ServiceBuilder::new()
+            .layer(RetryLayer::new(backoff, |r: &Result<String>| r.is_err()))
+            .service(flaky_service(calls.clone(), 2));
+        let resp = service.oneshot("world".to_string()).await?;
+        assert_eq!("hello, world!", resp);
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_layer_exhausted() -> Result<()> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let service = ServiceBuilder::new()
+            .layer(RetryLayer::new(backoff, |r: &Result<String>| r.is_err()))
+            .service(flaky_service(calls.clone(), 10));
+        let err = service.oneshot("world".to_string()).await.unwrap_err();
+        assert_eq!("unavailable", err.to_string());
+        assert_eq!(4, calls.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_retry_layer_on_response() -> Result<()> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let backoff = ConstantBuilder::default().with_delay(Duration::from_millis(1));
+        let service = ServiceBuilder::new()
+            .layer(RetryLayer::new(
+                backoff,
+                |r: &Result<String>| matches!(r, Ok(resp) if resp.is_empty()),
+            ))
+            .service(service_fn(|_: String| {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                async move { Ok::<_, anyhow::Error>("x".repeat(n)) }
+            }));
+        let resp = service.oneshot("world".to_string()).await?;
+        assert_eq!("x", resp);
+
+        Ok(())
+    }
+}
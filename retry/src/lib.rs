@@ -5,7 +5,7 @@ use std::{
     time::Duration,
 };
 
-use opresult::OperationResult;
+pub use opresult::OperationResult;
 
 pub mod delay;
 mod opresult;
@@ -14,6 +14,16 @@ impl<T, E> From<Result<T, E>> for OperationResult<T, E> {
 }
 
 impl<T, E> OperationResult<T, E> {
+    /// Retry only the errors accepted by `retryable`, e.g. a derived `is_retryable`,
+    /// other errors stop the retry at once.
+    pub fn classify(result: Result<T, E>, retryable: impl Fn(&E) -> bool) -> Self {
+        match result {
+            Ok(v) => OperationResult::Ok(v),
+            Err(e) if retryable(&e) => OperationResult::Retry(e),
+            Err(e) => OperationResult::Err(e),
+        }
+    }
+
     pub fn is_ok(&self) -> bool {
         matches!(self, Self::Ok(_))
     }
@@ -1,4 +1,5 @@
 use gloo_net::http::Request;
+use retry_backon::{exponential::ExponentialBuilder, retry::Retryable};
 use serde::Deserialize;
 use yew::prelude::*;
 
@@ -11,13 +12,16 @@ fn app() -> Html {
             move |_| {
                 let videos = videos.clone();
                 wasm_bindgen_futures::spawn_local(async move {
-                    let fetched_videos: Vec<Video> = Request::get("/tutorial/data.json")
-                        .send()
-                        .await
-                        .unwrap()
-                        .json()
-                        .await
-                        .unwrap();
+                    let fetched_videos: Vec<Video> = (|| async {
+                        Request::get("/tutorial/data.json")
+                            .send()
+                            .await?
+                            .json()
+                            .await
+                    })
+                    .retry(&ExponentialBuilder::default())
+                    .await
+                    .unwrap();
                     videos.set(fetched_videos);
                 });
                 || ()